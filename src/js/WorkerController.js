@@ -1,24 +1,42 @@
 const EventEmitter = require('events').EventEmitter;
 
+// Drives one machine in the worker (the worker can run several, so
+// messages are tagged with the machine's id)
 class WorkerWrapper extends EventEmitter {
   constructor(url) {
     super();
+    this.id = null;
+    this._loading = false;
     this._worker = new Worker(url);
-    this._worker.onmessage = ev => this.emit(ev.data.type, ev.data.msg);
+
+    this._worker.onmessage = (ev) => {
+      const { type, msg, id } = ev.data;
+
+      // a load gets the id of the new machine back
+      if (type === 'loaded' && this._loading) {
+        this._loading = false;
+        this.id = id;
+      }
+
+      if (id === this.id) this.emit(type, msg);
+    };
   }
 
   send(type, msg, transfer) {
-    this._worker.postMessage({ type, msg }, transfer);
+    this._worker.postMessage({ type, msg, id: this.id }, transfer);
   }
 
   sendAnd(type, msg, transfer) {
     return new Promise((resolve) => {
       this.once(type, value => resolve(value));
-      this._worker.postMessage({ type, msg }, transfer);
+      this.send(type, msg, transfer);
     });
   }
 
   load(filename, file) {
+    if (this.id !== null) this.send('close');
+
+    this._loading = true;
     this.send('load', { filename, file }, [file]);
   }
 
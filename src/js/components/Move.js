@@ -6,12 +6,28 @@ import Listener from '../Listener';
 import Spoken from './Spoken';
 
 
-function decodeEntities(str) {
-  const textarea = measure._textarea || document.createElement('textarea');
-  if (!measure._textarea) measure._textarea = textarea;
-
-  textarea.innerHTML = str;
-  return textarea.value;
+// printed text comes in as tokens (see src/rust/ui_web.rs), rendered as
+// plain text nodes so nothing the game prints is taken as markup
+function renderTokens(tokens, room) {
+  return tokens.map((token, index) => {
+    switch (token.type) {
+      case 'newline':
+        return <br key={index} />;
+
+      case 'object':
+        return (
+          <span className={(token.number === room) ? 'room' : 'object'} key={index}>
+            {token.name}
+          </span>
+        );
+
+      case 'debug':
+        return <span className="debug" key={index}>{token.text}</span>;
+
+      default:
+        return <span className={token.style.join(' ') || null} key={index}>{token.text}</span>;
+    }
+  });
 }
 
 
@@ -99,15 +115,16 @@ class Move extends Component {
     }
   }
 
-  calculateWidth(text) {
+  calculateWidth(tokens) {
     // get pixel length of the last line to set the width of the input
-    const line = text
-      .split('<br>')
-      .pop()
-      .replace(/<span>/g, '')
-      .replace(/<\/span>/g, '');
+    const newline = tokens.map(token => token.type).lastIndexOf('newline');
+
+    const line = tokens
+      .slice(newline + 1)
+      .map(token => token.text || token.name || '')
+      .join('');
 
-    const width = measure(decodeEntities(line), '15px Lora');
+    const width = measure(line, '15px Lora');
     return `calc(100% - ${width}px - 6px)`;
   }
 
@@ -181,13 +198,13 @@ class Move extends Component {
   }
 
   render() {
-    const width = this.calculateWidth(this.props.text);
+    const width = this.calculateWidth(this.props.tokens);
     const active = this.props.isActive && !this.props.modalOpen && !this.props.quit;
 
     return (
       <div className="move">
         <Spoken isEnabled={this.props.isRead}>
-          <span dangerouslySetInnerHTML={{ __html: this.props.text }} />
+          <span>{renderTokens(this.props.tokens, this.props.room)}</span>
         </Spoken>
 
         <span className="user-input" style={{ display: (active) ? 'none' : null }}>
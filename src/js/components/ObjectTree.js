@@ -11,13 +11,13 @@ class ObjectTree extends Component {
     this.tree.showDetails = this.props.showDetails;
 
     if (this.props.data && this.props.isVisible) {
-      setTimeout(() => this.tree.update(JSON.parse(this.props.data)));
+      setTimeout(() => this.tree.update(this.props.data));
     }
   }
 
   componentDidUpdate() {
     if (this.props.isVisible) {
-      this.tree.update(JSON.parse(this.props.data));
+      this.tree.update(this.props.data);
       this.tree.getDetails = this.props.getDetails;
       this.tree.showDetails = this.props.showDetails;
     }
@@ -71,7 +71,7 @@ class Settings extends Component {
 
   render() {
     const url = window.location.href.split('?').shift();
-    const link = encodeURI(`${url}?save=${this.props.currentSave}`);
+    const link = encodeURI(`${url}?save=${JSON.stringify(this.props.currentSave)}`);
 
     return (
       <div className="modal-body">
@@ -0,0 +1,35 @@
+import React from 'react';
+import { connect } from 'react-redux';
+
+
+const Stuck = props => (
+  <div>
+    <h2>Still running...</h2>
+
+    <div className="modal-body pt-4">
+      <p>
+        The game has been running for a while without printing anything,
+        it might be stuck in a loop.
+      </p>
+    </div>
+
+    <div className="modal-footer">
+      <button onClick={props.abandon}>
+        Undo
+      </button>
+
+      <button className="inverted" onClick={props.keepWaiting}>
+        Keep waiting
+      </button>
+    </div>
+  </div>
+);
+
+
+export default connect(
+  null,
+  dispatch => ({
+    keepWaiting: () => dispatch({ type: 'TS::CONTINUE' }),
+    abandon: () => dispatch({ type: 'TS::ABANDON' }),
+  }),
+)(Stuck);
@@ -3,6 +3,7 @@ import WorkerController from './WorkerController';
 import { Graph } from './Rooms';
 
 import Restore from './components/Restore';
+import Stuck from './components/Stuck';
 import ErrorModal from './components/ErrorModal';
 
 
@@ -69,6 +70,30 @@ let storage; // file specific localstorage
 let graph;
 let saves = [];
 let last_input = '';
+let objects = new Map(); // object table, by number
+let treeVersion = 0;
+
+
+// nests the object table for the tree view, top level objects at the root
+function buildTree(table) {
+  const build = (number) => {
+    const { name, children } = table.get(number);
+
+    return {
+      number,
+      name,
+      children: children.filter(child => table.has(child)).map(build),
+    };
+  };
+
+  const top = [...table.values()].filter(obj => obj.parent === 0);
+
+  return {
+    number: 0,
+    name: '(Null Object)',
+    children: top.map(obj => build(obj.number)),
+  };
+}
 
 
 const middleware = store => next => (action) => {
@@ -80,10 +105,12 @@ const middleware = store => next => (action) => {
     // * serialize/compress can interrupt rendering, so put it off
     const [saveMap, cancelSave] = debounce(() => storage.set('map', graph.serialize()));
 
-    worker.on('print', (text) => {
-      dispatch({ type: 'TS::TEXT', text });
+    worker.on('print', ({ tokens, room }) => {
+      dispatch({ type: 'TS::TEXT', tokens, room });
       cancelSave();
 
+      const text = tokens.map(token => token.text || token.name || '').join('');
+
       if (!!~text.indexOf('You have died')) {
         last_input = 'DIED';
       }
@@ -94,7 +121,7 @@ const middleware = store => next => (action) => {
 
     // short timer here to make sure the text gets rendered quickest
     worker.on('map', data => setTimeout(() => {
-      const [id, name] = JSON.parse(data);
+      const [id, name] = data;
 
       if (graph && !graph.isCurrent(id)) {
         graph.moveTo(id, name, last_input);
@@ -104,9 +131,26 @@ const middleware = store => next => (action) => {
     }, 10));
 
     // short timer here too
-    worker.on('tree', data => setTimeout(() => {
+    // (only changed objects come over, a full table if a diff was missed)
+    worker.on('tree', update => setTimeout(() => {
+      if (update.type === 'full') {
+        objects = new Map();
+      } else if (update.version !== treeVersion + 1) {
+        worker.send('tree:full');
+        return;
+      }
+
+      update.objects.forEach(obj => objects.set(obj.number, obj));
+      treeVersion = update.version;
+
+      const data = buildTree(objects);
       dispatch({ type: 'TREE::DATA', data });
-      storage.set('tree', data);
+      storage.set('tree', JSON.stringify(data));
+    }, 10));
+
+    // undo history tree (for branching timelines)
+    worker.on('history', data => setTimeout(() => {
+      dispatch({ type: 'HISTORY::DATA', data });
     }, 10));
 
     // here too
@@ -118,7 +162,7 @@ const middleware = store => next => (action) => {
     worker.on('savestate', (save) => {
       dispatch({ type: 'SAVES::STATE', save });
       // edge case: don't save state at very start of a game
-      if (!!last_input) storage.set('savestate', save);
+      if (!!last_input) storage.set('savestate', JSON.stringify(save));
     });
 
     worker.on('save', (save) => {
@@ -131,6 +175,11 @@ const middleware = store => next => (action) => {
       dispatch({ type: 'MODAL::SHOW', child: <Restore /> });
     });
 
+    // probably an infinite loop, offer to undo the command that started it
+    worker.on('stuck', () => {
+      dispatch({ type: 'MODAL::SHOW', child: <Stuck /> });
+    });
+
     worker.on('error', (err) => {
       dispatch({ type: 'MODAL::SHOW', child: <ErrorModal err={err} /> });
     });
@@ -154,7 +203,7 @@ const middleware = store => next => (action) => {
     const getDetails = id => worker.sendAnd('getDetails', id);
 
     dispatch({ type: 'MAP::CREATE', graph });
-    dispatch({ type: 'TREE::DATA', data: storage.get('tree') || '{}' });
+    dispatch({ type: 'TREE::DATA', data: JSON.parse(storage.get('tree') || '{}') });
     dispatch({ type: 'TREE::DETAILS', getDetails });
     dispatch({ type: 'SAVES::LOAD', saves });
 
@@ -238,6 +287,19 @@ const middleware = store => next => (action) => {
       next(action);
       break;
 
+    case 'TS::CONTINUE':
+      worker.send('continue');
+      dispatch({ type: 'MODAL::HIDE' });
+      break;
+
+    case 'TS::ABANDON':
+      last_input = 'UNDO';
+      worker.send('undo');
+      dispatch({ type: 'MODAL::HIDE' });
+
+      next(action);
+      break;
+
     case 'TS::SUBMIT':
       last_input = action.input;
       worker.send('input', action.input);
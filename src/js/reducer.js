@@ -13,7 +13,7 @@ function transcript(state = initialTranscript, action) {
   switch (action.type) {
     case 'TS::TEXT':
       return Object.assign({}, state, {
-        moves: [...state.moves, { text: action.text, input: '' }],
+        moves: [...state.moves, { tokens: action.tokens, room: action.room, input: '' }],
         undos: [],
       });
 
@@ -30,6 +30,12 @@ function transcript(state = initialTranscript, action) {
         history,
       });
 
+    // the last command got stuck and was undone, so it never happened
+    case 'TS::ABANDON':
+      return Object.assign({}, state, {
+        moves: [...state.moves.slice(0, -1), Object.assign({}, state.moves.slice(-1)[0], { input: '' })],
+      });
+
     case 'TS::UNDO':
       return Object.assign({}, state, {
         undos: [...state.undos, state.moves.pop()],
@@ -43,7 +49,7 @@ function transcript(state = initialTranscript, action) {
       });
 
     case 'TS::HEADER':
-      const [left, right] = JSON.parse(action.data);
+      const [left, right] = action.data;
 
       return Object.assign({}, state, {
         header: { left, right },
@@ -82,7 +88,7 @@ function map(state = initialMap, action) {
 
 
 const initialTree = {
-  data: '{}',
+  data: {},
   getDetails: () => {},
 };
 
@@ -102,6 +108,26 @@ function tree(state = initialTree, action) {
 }
 
 
+const initialHistory = {
+  current: null,
+  nodes: [],
+};
+
+function history(state = initialHistory, action) {
+  switch (action.type) {
+    case 'HISTORY::DATA':
+      return action.data;
+
+    case 'TS::STOP':
+    case 'TS::RESTART':
+      return initialHistory;
+
+    default:
+      return state;
+  }
+}
+
+
 function instructions(state = { data: '' }, action) {
   const merge = (data) => {
     const all = state.data + data + '\n\n* WAITING FOR USER INPUT *\n';
@@ -177,6 +203,8 @@ const initialSaves = {
 
 function saves(state = initialSaves, action) {
   const toObj = ([id, data]) => ({ id, data });
+  // older saves were stored as json strings
+  const fromStorage = save => (typeof save === 'string') ? JSON.parse(save) : save;
 
   switch (action.type) {
     case 'SAVES::STATE':
@@ -184,12 +212,12 @@ function saves(state = initialSaves, action) {
 
     case 'SAVES::LOAD':
       return Object.assign({}, state, {
-        saves: action.saves.map(JSON.parse).map(toObj),
+        saves: action.saves.map(fromStorage).map(toObj),
       });
 
     case 'SAVES::INSTR':
       return Object.assign({}, state, {
-        saves: [...state.saves, toObj(JSON.parse(action.save))]
+        saves: [...state.saves, toObj(action.save)]
       });
 
     default:
@@ -202,6 +230,7 @@ export default combineReducers({
   transcript,
   map,
   tree,
+  history,
   instructions,
   settings,
   saves,
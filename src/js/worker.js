@@ -1,145 +1,236 @@
-import { Wrapper, rust } from 'wasm-ffi';
+/* global wasm_bindgen */
 
+const root = (process.env.NODE_ENV === 'production') ? '/encrusted' : '';
 
-const wasmURL = (process.env.NODE_ENV === 'production')
-  ? '/encrusted/web.wasm'
-  : '/web.wasm';
+// wasm-bindgen's generated glue (--target no-modules), sets up `wasm_bindgen`
+importScripts(`${root}/encrusted.js`);
 
 
-// hold onto active file in case of restarts
-let file = null;
+// every game running in this worker, by id. messages in and out carry the id
+// of the machine they're for, so a page can run several side by side
+const machines = new Map();
+let nextId = 1;
 
 
-function sendWorkerMessage(type, msg) {
-  postMessage({ type, msg });
+function sendWorkerMessage(type, msg, id) {
+  postMessage({ type, msg, id });
 }
 
 
-const zmachine = new Wrapper({
-  hook: [],
-  create: [null, ['number', 'number']],
-  feed: [null, ['string']],
-  step: ['bool'],
-  undo: ['bool'],
-  redo: ['bool'],
-  get_updates: [],
-  restore: [null, ['string']],
-  load_savestate: [null, ['string']],
-  enable_instruction_logs: [null, ['bool']],
-  get_object_details: [rust.string, ['number']],
-  flush_log: [],
-});
-
-
-zmachine.imports(wrap => ({
-  env: {
-    js_message: wrap('string', 'string', sendWorkerMessage),
-
-    trace: wrap('string', (msg) => {
-      const err = new Error(msg);
-
-      setTimeout(() => {
-        zmachine.flush_log();
-      }, 200);
-
-      postMessage({
-        type: 'error',
-        msg: { msg, stack: err.stack }
-      });
-    }),
-
-    rand: function() {
-      return Math.floor(Math.random() * 0xFFFF);
+class Machine {
+  constructor(id, file, fromZvm) {
+    this.id = id;
+    this.file = file; // held onto in case of restarts
+    this.printed = []; // tokens from the current step (sent along with the room)
+    this.savestateTimer = null;
+    this.stepTimer = null;
+    this.status = null; // from the last step
+
+    const onMessage = this.onMessage.bind(this);
+
+    this.zvm = (fromZvm)
+      ? fromZvm.fork(onMessage)
+      : new wasm_bindgen.ZMachine(file, onMessage);
+  }
+
+  send(type, msg) {
+    sendWorkerMessage(type, msg, this.id);
+  }
+
+  // messages coming out of the zmachine (printed text, header, saves, etc.)
+  onMessage(type, value) {
+    if (type === 'print') {
+      this.printed = this.printed.concat(value);
+      return;
     }
-  },
-}));
 
+    if (type === 'error') {
+      const err = new Error(value);
+      this.send('error', { msg: value, stack: err.stack });
+      return;
+    }
+
+    this.send(type, value);
+  }
+
+  // snapshots are only materialized from the undo history when needed,
+  // so wait for a pause in input before sending the autosave
+  queueSavestate() {
+    clearTimeout(this.savestateTimer);
+    this.savestateTimer = setTimeout(() => this.zvm.send_savestate(), 500);
+  }
+
+  sendUpdates() {
+    this.send('map', this.zvm.room());
+    this.send('history', this.zvm.history());
+
+    const tree = this.zvm.object_tree_update();
+    if (tree) this.send('tree', tree);
+
+    const instructions = this.zvm.take_instructions();
+    if (instructions) this.send('instructions', instructions);
+  }
+
+  // long running turns are done a slice at a time (as much as the
+  // instruction budget allows) so other messages can get through in between
+  step() {
+    clearTimeout(this.stepTimer);
+    this.status = this.zvm.step();
+
+    // object names that are the current room get shown as headings
+    if (this.printed.length) {
+      const [room] = this.zvm.room();
+
+      this.send('print', { tokens: this.printed, room });
+      this.printed = [];
+    }
+
+    if (this.status === 'running') {
+      this.stepTimer = setTimeout(() => this.step());
+      return;
+    }
+
+    this.sendUpdates();
+
+    if (this.status === 'stuck') this.send('stuck');
+    if (this.status === 'quit') this.send('quit');
+  }
+
+  // still in the middle of a turn (can't take input yet, but a "break" in
+  // the debugger takes $ commands like $step and $continue as input)
+  isBusy() {
+    return this.status === 'running' || this.status === 'stuck';
+  }
+
+  free() {
+    clearTimeout(this.savestateTimer);
+    clearTimeout(this.stepTimer);
+    this.zvm.free();
+  }
+}
 
-function step() {
-  const done = zmachine.step();
-  if (done) sendWorkerMessage('quit');
+
+function create(file, from) {
+  const id = nextId++;
+  const machine = new Machine(id, file, from && from.zvm);
+
+  machines.set(id, machine);
+  return machine;
 }
 
 
+let instance = null;
+
 function instantiate() {
-  if (zmachine.exports) return Promise.resolve();
-  return zmachine.fetch(wasmURL).then(() => zmachine.hook());
+  if (!instance) instance = wasm_bindgen(`${root}/encrusted_bg.wasm`);
+  return instance;
 }
 
 
 // dispatch handlers based on incoming messages
 onmessage = (ev) => {
+  const { type, msg, id } = ev.data;
+  const machine = machines.get(id);
+
   // only want to compile/load the module once
-  if (ev.data.type === 'instantiate') {
+  if (type === 'instantiate') {
     instantiate().catch(err => setTimeout(() => {
       console.log('Error starting wasm: ', err, err.stack);
     }));
   }
 
-  if (ev.data.type === 'load') {
+  // replies with the id of the new machine
+  if (type === 'load') {
     instantiate()
-      .then(() => {
-        file = new Uint8Array(ev.data.msg.file);
-        const file_ptr = zmachine.utils.writeArray(file);
-
-        zmachine.create(file_ptr, file.length);
-        sendWorkerMessage('loaded');
-      })
+      .then(() => create(new Uint8Array(msg.file)).send('loaded'))
       .catch(err => setTimeout(() => {
         console.log('Error starting wasm: ', err, err.stack);
       }));
   }
 
-  if (ev.data.type === 'start') {
-    step();
+  // everything else is for an existing machine
+  if (!machine) return;
+
+  // a copy of the game as it is now, replies with the id of the copy
+  if (type === 'fork') {
+    const copy = create(machine.file, machine);
+
+    copy.send('loaded');
+    copy.step();
   }
 
-  if (ev.data.type === 'restart') {
-    const file_ptr = zmachine.utils.writeArray(file);
+  if (type === 'close') {
+    machine.free();
+    machines.delete(id);
+  }
 
-    zmachine.create(file_ptr, file.length);
-    sendWorkerMessage('loaded');
+  if (type === 'start') {
+    machine.step();
   }
 
-  if (ev.data.type === 'input') {
-    zmachine.feed(ev.data.msg);
-    step();
+  if (type === 'restart') {
+    const fresh = new Machine(id, machine.file);
+
+    machine.free();
+    machines.set(id, fresh);
+    fresh.send('loaded');
   }
 
-  if (ev.data.type === 'restore') {
-    zmachine.restore(ev.data.msg);
-    step();
+  if (type === 'input') {
+    if (machine.isBusy()) return;
+
+    machine.zvm.feed(msg);
+    machine.step();
+    machine.queueSavestate();
   }
 
-  if (ev.data.type === 'load_savestate') {
-    zmachine.load_savestate(ev.data.msg);
-    step();
-    zmachine.feed('look'); // get description text and then undo
-    step();
-    zmachine.undo();
+  if (type === 'restore') {
+    machine.zvm.restore(msg);
+    machine.step();
   }
 
-  if (ev.data.type === 'undo') {
-    const ok = zmachine.undo();
+  if (type === 'load_savestate') {
+    machine.zvm.load_savestate(msg);
+    machine.step();
+    machine.zvm.feed('look'); // get description text and then undo
+    machine.step();
+    machine.zvm.undo();
+    machine.step();
+  }
 
-    sendWorkerMessage('undo', ok);
-    zmachine.get_updates();
+  // keep going after a "stuck" step
+  if (type === 'continue') {
+    machine.step();
   }
 
-  if (ev.data.type === 'redo') {
-    const ok = zmachine.redo();
+  // stepping after an undo/redo pauses at the restored read again
+  // (an undo in the middle of a turn goes back to the start of it)
+  if (type === 'undo') {
+    const ok = machine.zvm.undo();
+
+    machine.send('undo', ok);
+    machine.step();
+  }
+
+  if (type === 'redo') {
+    if (machine.isBusy()) return;
+
+    const ok = machine.zvm.redo();
+
+    machine.send('redo', ok);
+    machine.step();
+  }
 
-    sendWorkerMessage('redo', ok);
-    zmachine.get_updates();
+  if (type === 'enable:instructions') {
+    machine.zvm.set_instruction_logs(!!msg);
   }
 
-  if (ev.data.type === 'enable:instructions') {
-    zmachine.enable_instruction_logs(!!ev.data.msg);
+  // the whole tree, for when the page missed a diff
+  if (type === 'tree:full') {
+    machine.send('tree', machine.zvm.object_tree());
   }
 
-  if (ev.data.type === 'getDetails') {
-    const str = zmachine.get_object_details(ev.data.msg);
-    sendWorkerMessage('getDetails', str.value);
-    str.free();
+  if (type === 'getDetails') {
+    machine.send('getDetails', machine.zvm.object_details(msg));
   }
 };
@@ -0,0 +1,596 @@
+use std::collections::{BTreeMap, HashMap};
+
+use base64;
+
+// The names from an Inform debug information file: the XML gameinfo.dbg that
+// Inform 6.33 on writes with -k. (Older compilers wrote a binary format, which
+// isn't read here.) Everything is keyed the way the interpreter sees it:
+// routines by byte address, globals by number, objects/attributes/properties
+// by number, source lines by the address their code starts at
+#[derive(Debug, Default)]
+pub struct DebugInfo {
+    // the first 64 bytes of the story file it was made with
+    pub prefix: Vec<u8>,
+    routines: BTreeMap<usize, Routine>,
+    globals: BTreeMap<u8, String>,
+    objects: HashMap<u16, String>,
+    attributes: HashMap<u16, String>,
+    properties: HashMap<u16, String>,
+    // source file paths by index
+    sources: HashMap<usize, String>,
+    // sequence points: where each statement's code starts, and its (file
+    // index, line)
+    lines: BTreeMap<usize, (usize, usize)>,
+}
+
+#[derive(Debug)]
+struct Routine {
+    name: String,
+    // local variable names, by index (local0 first)
+    locals: Vec<Option<String>>,
+}
+
+impl DebugInfo {
+    pub fn empty() -> DebugInfo {
+        DebugInfo::default()
+    }
+
+    // globals in the file are given by address, so it takes where the
+    // globals table starts to number them
+    pub fn parse(data: &[u8], globals_addr: usize) -> Result<DebugInfo, String> {
+        if data.starts_with(&[0xDE, 0xBF]) {
+            return Err(String::from(
+                "This is an old binary debug file, only the XML kind (Inform 6.33 on) can be read",
+            ));
+        }
+
+        let document = Element::parse(&String::from_utf8_lossy(data))?;
+        let root = match document.children.first() {
+            Some(root) if root.name == "inform-story-file" => root,
+            _ => return Err(String::from("Not an Inform debug file (no <inform-story-file>)")),
+        };
+
+        let mut info = DebugInfo::empty();
+
+        for element in &root.children {
+            let name = element.text("identifier");
+
+            match (element.name.as_str(), name) {
+                ("source", _) => {
+                    let index = element.attribute("index").and_then(|index| index.parse().ok());
+                    let path = element.text("given-path").or_else(|| element.text("resolved-path"));
+
+                    if let (Some(index), Some(path)) = (index, path) {
+                        info.sources.insert(index, path.to_string());
+                    }
+                }
+                ("sequence-point", _) => info.add_sequence_point(element),
+                ("story-file-prefix", _) => {
+                    let prefix: String = element.body.split_whitespace().collect();
+                    info.prefix = base64::decode(&prefix)
+                        .map_err(|_| String::from("Bad <story-file-prefix> in the debug file"))?;
+                }
+                ("routine", Some(name)) => {
+                    let addr = match element.number("address") {
+                        Some(addr) => addr,
+                        None => continue,
+                    };
+
+                    let mut locals = Vec::new();
+
+                    for local in element.children.iter().filter(|el| el.name == "local-variable") {
+                        if let (Some(local_name), Some(index)) = (local.text("identifier"), local.number("index")) {
+                            if (1..=15).contains(&index) {
+                                locals.resize(locals.len().max(index), None);
+                                locals[index - 1] = Some(local_name.to_string());
+                            }
+                        }
+                    }
+
+                    for point in element.children.iter().filter(|el| el.name == "sequence-point") {
+                        info.add_sequence_point(point);
+                    }
+
+                    let name = name.to_string();
+                    info.routines.insert(addr, Routine { name, locals });
+                }
+                ("global-variable", Some(name)) => match element.number("address") {
+                    Some(addr) if (globals_addr..globals_addr + 480).contains(&addr) => {
+                        let index = ((addr - globals_addr) / 2) as u8;
+                        info.globals.insert(index, name.to_string());
+                    }
+                    _ => (),
+                },
+                ("object", Some(name)) => {
+                    if let Some(num) = element.number("value") {
+                        info.objects.insert(num as u16, name.to_string());
+                    }
+                }
+                ("attribute", Some(name)) => {
+                    if let Some(num) = element.number("value") {
+                        info.attributes.insert(num as u16, name.to_string());
+                    }
+                }
+                ("property", Some(name)) => {
+                    if let Some(num) = element.number("value") {
+                        info.properties.insert(num as u16, name.to_string());
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(info)
+    }
+
+    fn add_sequence_point(&mut self, point: &Element) {
+        let location = point.children.iter().find(|el| el.name == "source-code-location");
+        let line = location.and_then(|location| {
+            match (location.number("file-index"), location.number("line")) {
+                (Some(file), Some(line)) => Some((file, line)),
+                _ => None,
+            }
+        });
+
+        if let (Some(addr), Some(line)) = (point.number("address"), line) {
+            self.lines.insert(addr, line);
+        }
+    }
+
+    // "file.inf:123" for the statement the code at addr belongs to (as long as
+    // it's in the same routine)
+    pub fn source_line(&self, addr: usize) -> Option<String> {
+        let (&routine, _) = self.routines.range(..=addr).next_back()?;
+        let (&start, &(file, line)) = self.lines.range(..=addr).next_back()?;
+
+        if start < routine {
+            return None;
+        }
+
+        let path = self.sources.get(&file).map_or("?", String::as_str);
+        Some(format!("{}:{}", path, line))
+    }
+
+    // where the code for "file.inf:123" starts (the file can be given without
+    // its directory)
+    pub fn line_addr(&self, location: &str) -> Option<usize> {
+        let colon = location.rfind(':')?;
+        let (file, line) = (&location[..colon], location[colon + 1..].parse::<usize>().ok()?);
+
+        let in_file = |index: &usize| match self.sources.get(index) {
+            Some(path) => {
+                path == file || path.ends_with(&format!("/{}", file)) || path.ends_with(&format!("\\{}", file))
+            }
+            None => false,
+        };
+
+        self.lines
+            .iter()
+            .find(|&(_, &(index, at))| at == line && in_file(&index))
+            .map(|(addr, _)| *addr)
+    }
+
+    // (byte addresses)
+    pub fn routine_addrs(&self) -> Vec<usize> {
+        self.routines.keys().cloned().collect()
+    }
+
+    pub fn routine_name(&self, addr: usize) -> Option<&str> {
+        self.routines.get(&addr).map(|routine| routine.name.as_str())
+    }
+
+    pub fn routine_addr(&self, name: &str) -> Option<usize> {
+        self.routines
+            .iter()
+            .find(|&(_, routine)| routine.name.eq_ignore_ascii_case(name))
+            .map(|(addr, _)| *addr)
+    }
+
+    // (index 0 is local0)
+    pub fn local_name(&self, routine: usize, index: usize) -> Option<&str> {
+        self.routines
+            .get(&routine)
+            .and_then(|routine| routine.locals.get(index))
+            .and_then(|name| name.as_ref().map(String::as_str))
+    }
+
+    pub fn local_index(&self, routine: usize, name: &str) -> Option<usize> {
+        self.routines.get(&routine).and_then(|routine| {
+            routine
+                .locals
+                .iter()
+                .position(|local| local.as_ref().map(String::as_str) == Some(name))
+        })
+    }
+
+    pub fn has_globals(&self) -> bool {
+        !self.globals.is_empty()
+    }
+
+    pub fn global_name(&self, index: u8) -> Option<&str> {
+        self.globals.get(&index).map(String::as_str)
+    }
+
+    pub fn global_index(&self, name: &str) -> Option<u8> {
+        find_name(self.globals.iter(), name)
+    }
+
+    pub fn object_number(&self, name: &str) -> Option<u16> {
+        find_name(self.objects.iter(), name)
+    }
+
+    pub fn attribute_name(&self, num: u16) -> Option<&str> {
+        self.attributes.get(&num).map(String::as_str)
+    }
+
+    pub fn attribute_number(&self, name: &str) -> Option<u16> {
+        find_name(self.attributes.iter(), name)
+    }
+
+    pub fn property_name(&self, num: u16) -> Option<&str> {
+        self.properties.get(&num).map(String::as_str)
+    }
+
+    pub fn property_number(&self, name: &str) -> Option<u16> {
+        find_name(self.properties.iter(), name)
+    }
+}
+
+// (Inform identifiers aren't case sensitive)
+fn find_name<'a, K, I>(mut entries: I, name: &str) -> Option<K>
+where
+    K: Copy + 'a,
+    I: Iterator<Item = (&'a K, &'a String)>,
+{
+    entries
+        .find(|&(_, entry)| entry.eq_ignore_ascii_case(name))
+        .map(|(key, _)| *key)
+}
+
+// Just enough XML for a debug file: elements, their attributes and their
+// text. Comments, the <?xml?> declaration and doctypes are skipped
+#[derive(Debug)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    body: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn new(name: &str) -> Element {
+        Element {
+            name: name.to_string(),
+            attributes: Vec::new(),
+            body: String::new(),
+            children: Vec::new(),
+        }
+    }
+
+    // an unnamed element holding the document's top-level elements
+    fn parse(text: &str) -> Result<Element, String> {
+        let mut stack = vec![Element::new("")];
+        let mut rest = text;
+
+        let skip_past = |rest: &str, end: &str| match rest.find(end) {
+            Some(index) => Ok(index + end.len()),
+            None => Err(format!("Debug file ends inside {}", rest.chars().take(20).collect::<String>())),
+        };
+
+        while let Some(start) = rest.find('<') {
+            stack.last_mut().unwrap().body.push_str(&unescape(&rest[..start]));
+            rest = &rest[start..];
+
+            let end = if rest.starts_with("<!--") {
+                skip_past(rest, "-->")?
+            } else if rest.starts_with("<![CDATA[") {
+                // (as it is, no entities)
+                let end = skip_past(rest, "]]>")?;
+                stack.last_mut().unwrap().body.push_str(&rest[9..end - 3]);
+                end
+            } else if rest.starts_with("<?") {
+                skip_past(rest, "?>")?
+            } else if rest.starts_with("<!") {
+                skip_past(rest, ">")?
+            } else {
+                let end = skip_past(rest, ">")?;
+                let tag = &rest[1..end - 1];
+
+                if let Some(name) = tag.strip_prefix('/') {
+                    let element = stack.pop().unwrap();
+
+                    if stack.is_empty() || element.name != name.trim() {
+                        return Err(format!("Unexpected </{}> in the debug file", name.trim()));
+                    }
+
+                    stack.last_mut().unwrap().children.push(element);
+                } else {
+                    let name = tag
+                        .split(|c: char| c.is_whitespace() || c == '/')
+                        .next()
+                        .unwrap();
+                    let mut element = Element::new(name);
+                    element.attributes = Element::parse_attributes(&tag[name.len()..]);
+
+                    if tag.ends_with('/') {
+                        stack.last_mut().unwrap().children.push(element);
+                    } else {
+                        stack.push(element);
+                    }
+                }
+
+                end
+            };
+
+            rest = &rest[end..];
+        }
+
+        if stack.len() > 1 {
+            return Err(format!("Debug file ends inside <{}>", stack.last().unwrap().name));
+        }
+
+        Ok(stack.pop().unwrap())
+    }
+
+    // name="value" pairs (or with single quotes)
+    fn parse_attributes(text: &str) -> Vec<(String, String)> {
+        let mut attributes = Vec::new();
+        let mut rest = text;
+
+        while let Some(equals) = rest.find('=') {
+            let name = rest[..equals].trim();
+            let value = rest[equals + 1..].trim_start();
+
+            let quote = match value.chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => quote,
+                _ => break,
+            };
+
+            let end = match value[1..].find(quote) {
+                Some(end) => end + 1,
+                None => break,
+            };
+
+            attributes.push((name.to_string(), unescape(&value[1..end])));
+            rest = &value[end + 1..];
+        }
+
+        attributes
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|&(attr, _)| attr == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // the (trimmed) text of the first child with this name
+    fn text(&self, name: &str) -> Option<&str> {
+        self.children
+            .iter()
+            .find(|child| child.name == name)
+            .map(|child| child.body.trim())
+    }
+
+    fn number(&self, name: &str) -> Option<usize> {
+        self.text(name).and_then(|text| text.parse().ok())
+    }
+}
+
+fn unescape(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+
+    let mut out = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+
+        let entity = &rest[1..end];
+        let ch = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => if let Some(hex) = entity.strip_prefix("#x") {
+                u32::from_str_radix(hex, 16).ok()
+            } else if let Some(dec) = entity.strip_prefix('#') {
+                dec.parse().ok()
+            } else {
+                None
+            }.and_then(::std::char::from_u32),
+        };
+
+        match ch {
+            Some(ch) => {
+                out.push(ch);
+                rest = &rest[end + 1..];
+            }
+            // (left as it is)
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unescape, DebugInfo, Element};
+
+    const FIXTURE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE inform-story-file>
+<inform-story-file version="1.0" content-creator="Inform">
+  <!-- a comment with <tags> in it -->
+  <story-file-prefix>
+    AwAA
+  </story-file-prefix>
+  <source index="0">
+    <given-path>game.inf</given-path>
+    <resolved-path>/home/me/game.inf</resolved-path>
+    <language>Inform 6</language>
+  </source>
+  <source index='1'>
+    <given-path>lib/parser.h</given-path>
+  </source>
+  <global-variable>
+    <identifier>score</identifier>
+    <address>644</address>
+  </global-variable>
+  <global-variable>
+    <identifier>far_away</identifier>
+    <address>9000</address>
+  </global-variable>
+  <object>
+    <identifier>Fish&amp;Chips</identifier>
+    <value>12</value>
+  </object>
+  <attribute>
+    <identifier><![CDATA[light]]></identifier>
+    <value>9</value>
+  </attribute>
+  <property>
+    <identifier>description</identifier>
+    <value>3</value>
+  </property>
+  <routine>
+    <identifier>Initialise</identifier>
+    <address>4096</address>
+    <local-variable>
+      <identifier>i</identifier>
+      <index>1</index>
+    </local-variable>
+    <local-variable>
+      <identifier>obj</identifier>
+      <index>3</index>
+    </local-variable>
+    <sequence-point>
+      <address>4097</address>
+      <source-code-location>
+        <file-index>0</file-index>
+        <line>10</line>
+        <character>4</character>
+      </source-code-location>
+    </sequence-point>
+    <sequence-point>
+      <address>4104</address>
+      <source-code-location>
+        <file-index>0</file-index>
+        <line>11</line>
+      </source-code-location>
+    </sequence-point>
+  </routine>
+  <routine>
+    <identifier>Parser__parse</identifier>
+    <address>4200</address>
+    <sequence-point>
+      <address>4210</address>
+      <source-code-location>
+        <file-index>1</file-index>
+        <line>500</line>
+      </source-code-location>
+    </sequence-point>
+  </routine>
+</inform-story-file>
+"#;
+
+    fn fixture() -> DebugInfo {
+        DebugInfo::parse(FIXTURE.as_bytes(), 640).unwrap()
+    }
+
+    #[test]
+    fn reads_names() {
+        let info = fixture();
+
+        assert_eq!(info.prefix, vec![3, 0, 0]);
+        assert_eq!(info.routine_name(4096), Some("Initialise"));
+        assert_eq!(info.routine_addr("initialise"), Some(4096));
+        assert_eq!(info.routine_addrs(), vec![4096, 4200]);
+        assert_eq!(info.object_number("fish&chips"), Some(12));
+        assert_eq!(info.attribute_name(9), Some("light"));
+        assert_eq!(info.property_number("description"), Some(3));
+    }
+
+    #[test]
+    fn numbers_locals_and_globals() {
+        let info = fixture();
+
+        // (local1 is the first one, and local1 in the file is index 0 here)
+        assert_eq!(info.local_name(4096, 0), Some("i"));
+        assert_eq!(info.local_name(4096, 1), None);
+        assert_eq!(info.local_name(4096, 2), Some("obj"));
+        assert_eq!(info.local_index(4096, "obj"), Some(2));
+
+        // 644 is 2 words into the globals at 640, 9000 is past all of them
+        assert_eq!(info.global_name(2), Some("score"));
+        assert_eq!(info.global_index("SCORE"), Some(2));
+        assert_eq!(info.global_index("far_away"), None);
+    }
+
+    #[test]
+    fn maps_addresses_to_source_lines() {
+        let info = fixture();
+
+        assert_eq!(info.source_line(4097), Some(String::from("game.inf:10")));
+        assert_eq!(info.source_line(4100), Some(String::from("game.inf:10")));
+        assert_eq!(info.source_line(4150), Some(String::from("game.inf:11")));
+        assert_eq!(info.source_line(4212), Some(String::from("lib/parser.h:500")));
+
+        // before the first statement of a routine isn't in the one before it
+        assert_eq!(info.source_line(4205), None);
+        assert_eq!(info.source_line(100), None);
+
+        assert_eq!(info.line_addr("game.inf:11"), Some(4104));
+        assert_eq!(info.line_addr("parser.h:500"), Some(4210));
+        assert_eq!(info.line_addr("game.inf:12"), None);
+        assert_eq!(info.line_addr("game.inf"), None);
+    }
+
+    #[test]
+    fn rejects_what_it_cant_read() {
+        assert!(DebugInfo::parse(&[0xDE, 0xBF, 0x00, 0x00], 640).is_err());
+        assert!(DebugInfo::parse(b"<something-else></something-else>", 640).is_err());
+        assert!(DebugInfo::parse(b"<inform-story-file><routine></inform-story-file>", 640).is_err());
+        assert!(DebugInfo::parse(b"<inform-story-file><routine>", 640).is_err());
+        assert!(DebugInfo::parse(b"<inform-story-file><!-- never closed", 640).is_err());
+    }
+
+    #[test]
+    fn parses_elements() {
+        let text = "<a x=\"1\" y='&lt;2&gt;'>one<b/>two<![CDATA[ <3> & ]]></a>";
+        let root = Element::parse(text).unwrap();
+        let a = &root.children[0];
+
+        assert_eq!(a.name, "a");
+        assert_eq!(a.attribute("x"), Some("1"));
+        assert_eq!(a.attribute("y"), Some("<2>"));
+        assert_eq!(a.attribute("z"), None);
+        assert_eq!(a.body, "onetwo <3> & ");
+        assert_eq!(a.children.len(), 1);
+        assert_eq!(a.children[0].name, "b");
+    }
+
+    #[test]
+    fn unescapes_entities() {
+        assert_eq!(unescape("plain"), "plain");
+        assert_eq!(unescape("&lt;a&gt; &amp; &quot;b&quot; &apos;c&apos;"), "<a> & \"b\" 'c'");
+        assert_eq!(unescape("&#65;&#x42;&#x43;"), "ABC");
+
+        // anything it doesn't know is left alone
+        assert_eq!(unescape("&nbsp; & &#xZZ; &"), "&nbsp; & &#xZZ; &");
+    }
+}
@@ -4,22 +4,43 @@ use std::fmt;
 pub struct Frame {
     stack: Vec<u16>,
     locals: Vec<u16>,
+    // the routine's address, unpacked and as it was called (0 if it isn't
+    // known, like for frames that come from a save file)
+    pub routine: usize,
+    pub packed: u16,
+    // the values passed in, the first `arg_count` of them (also unknown for
+    // frames from a save file). kept inline, this is made on every call
+    args: Option<[u16; 7]>,
     pub arg_count: u8,
     pub resume: usize,
     pub store: Option<u8>,
 }
 
 impl Frame {
-    pub fn new(resume: usize, store: Option<u8>, mut locals: Vec<u16>, arguments: &[u16]) -> Frame {
+    pub fn new(
+        routine: usize,
+        packed: u16,
+        resume: usize,
+        store: Option<u8>,
+        mut locals: Vec<u16>,
+        arguments: &[u16],
+    ) -> Frame {
         for i in 0..locals.len() {
             if arguments.len() > i {
                 locals[i] = arguments[i];
             }
         }
 
+        let mut args = [0; 7];
+        let count = arguments.len().min(7);
+        args[..count].copy_from_slice(&arguments[..count]);
+
         Frame {
             stack: Vec::new(),
             arg_count: arguments.len() as u8,
+            routine,
+            packed,
+            args: Some(args),
             locals,
             resume,
             store,
@@ -30,6 +51,9 @@ impl Frame {
         Frame {
             stack: Vec::new(),
             locals: Vec::new(),
+            routine: 0,
+            packed: 0,
+            args: None,
             arg_count: 0,
             resume: 0,
             store: None,
@@ -87,6 +111,9 @@ impl Frame {
         Frame {
             stack,
             locals,
+            routine: 0,
+            packed: 0,
+            args: None,
             arg_count,
             resume,
             store,
@@ -113,6 +140,19 @@ impl Frame {
         self.locals[index] = value;
     }
 
+    pub fn args(&self) -> Option<&[u16]> {
+        let count = (self.arg_count as usize).min(7);
+        self.args.as_ref().map(|args| &args[..count])
+    }
+
+    pub fn locals(&self) -> &[u16] {
+        &self.locals
+    }
+
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
     pub fn stack_push(&mut self, value: u16) {
         self.stack.push(value);
     }
@@ -157,8 +197,9 @@ impl Frame {
         bytes.push(((self.resume & 0x00_FF00) >> 8) as u8);
         bytes.push((self.resume & 0x00_00FF) as u8);
 
-        let mut flags = self.locals.len() as u8; // 0b0000vvvv
-        if self.store.is_some() {
+        // (bit 4 is set when the result gets discarded)
+        let mut flags = self.locals.len() as u8; // 0b000pvvvv
+        if self.store.is_none() {
             flags += 0b0001_0000;
         }
 
@@ -194,3 +235,36 @@ impl fmt::Display for Frame {
         write!(f, "{}", self.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Frame;
+
+    // a Quetzal Stks frame: resume 001234, 2 locals, 1 arg, 1 stack value
+    fn stks_frame(flags: u8, store: u8) -> Vec<u8> {
+        vec![
+            0x00, 0x12, 0x34, flags, store, 0b0000_0001, 0x00, 0x01, 0x00, 0x05, 0x00, 0x00, 0xAB,
+            0xCD,
+        ]
+    }
+
+    #[test]
+    fn discarded_result_round_trips() {
+        let bytes = stks_frame(0b0001_0010, 0);
+        let frame = Frame::from_bytes(&bytes);
+
+        assert_eq!(frame.store, None);
+        assert_eq!(frame.to_vec(), bytes);
+        assert_eq!(Frame::from_bytes(&frame.to_vec()).store, None);
+    }
+
+    #[test]
+    fn stored_result_round_trips() {
+        let bytes = stks_frame(0b0000_0010, 0x10);
+        let frame = Frame::from_bytes(&bytes);
+
+        assert_eq!(frame.store, Some(0x10));
+        assert_eq!(frame.to_vec(), bytes);
+        assert_eq!(Frame::from_bytes(&frame.to_vec()).store, Some(0x10));
+    }
+}
@@ -0,0 +1,694 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use quetzal::{HistoryEntry, QuetzalSave};
+
+// every so often a state is stored against the original memory instead of
+// its parent, so rebuilding a state never has to walk back too far
+const KEYFRAME_INTERVAL: usize = 32;
+
+// A full machine state: program counter, the stack frames (encoded like a
+// Quetzal Stks chunk) and the entire dynamic memory region
+#[derive(Debug)]
+pub struct State {
+    pub pc: usize,
+    pub frames: Vec<u8>,
+    pub memory: Vec<u8>,
+}
+
+// A named point to come back to. These are kept apart from the undo history
+// as regular save files, so undo/redo and pruning old states don't touch them
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub name: String,
+    pub location: String,
+    pub status: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Memory {
+    // compressed against the original dynamic memory
+    Key(Vec<u8>),
+    // compressed against the parent state's memory
+    Delta(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Snapshot {
+    pc: usize,
+    frames: Vec<u8>,
+    memory: Memory,
+}
+
+impl Snapshot {
+    fn size(&self) -> usize {
+        self.frames.len() + match self.memory {
+            Memory::Key(ref bytes) | Memory::Delta(ref bytes) => bytes.len(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryNode {
+    pub id: usize,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub command: String,
+    pub location: String,
+    pub label: Option<String>,
+    #[serde(skip_serializing)]
+    depth: usize,
+    #[serde(skip_serializing)]
+    snapshot: Snapshot,
+    // the child that a redo moves to (the timeline that was last visited)
+    #[serde(skip_serializing)]
+    active_child: Option<usize>,
+}
+
+impl HistoryNode {
+    fn describe(&self) -> String {
+        let command = if self.command.is_empty() {
+            String::from("(start)")
+        } else {
+            format!("\"{}\"", self.command)
+        };
+
+        match self.label {
+            Some(ref label) => format!("#{} {} @ {} [{}]", self.id, command, self.location, label),
+            None => format!("#{} {} @ {}", self.id, command, self.location),
+        }
+    }
+}
+
+// Undo history, kept as a tree of save states. Each state is keyed by the
+// command that produced it, so going back and entering a different command
+// starts a new branch instead of throwing the old timeline away.
+//
+// States are stored as compressed deltas against their parent and only
+// rebuilt when they are needed (undo, redo, or exporting a save). When the
+// stored states go over the memory budget, the oldest ones get dropped.
+#[derive(Debug, Serialize)]
+pub struct History {
+    current: Option<usize>,
+    nodes: BTreeMap<usize, HistoryNode>,
+    #[serde(skip_serializing)]
+    next_id: usize,
+    #[serde(skip_serializing)]
+    original: Vec<u8>,
+    #[serde(skip_serializing)]
+    budget: usize,
+    // the last state that was rebuilt, which is usually the parent of the next one
+    #[serde(skip_serializing)]
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl History {
+    pub fn new(original: Vec<u8>, budget: usize) -> History {
+        History {
+            current: None,
+            nodes: BTreeMap::new(),
+            next_id: 0,
+            original,
+            budget,
+            cache: None,
+        }
+    }
+
+    pub fn current(&self) -> Option<&HistoryNode> {
+        self.current.map(|id| &self.nodes[&id])
+    }
+
+    pub fn parent(&self) -> Option<&HistoryNode> {
+        self.current()
+            .and_then(|node| node.parent)
+            .map(|id| &self.nodes[&id])
+    }
+
+    // adds a new state after the current one (or moves to an existing branch
+    // if this command has been entered from the current state before)
+    pub fn push(&mut self, command: &str, location: String, state: State) {
+        let parent = match self.current {
+            Some(id) => id,
+            None => {
+                let id = self.add_node(None, command, location, state);
+                self.current = Some(id);
+                return;
+            }
+        };
+
+        let existing = self.nodes[&parent]
+            .children
+            .iter()
+            .find(|child| self.nodes[child].command == command)
+            .cloned();
+
+        let id = if let Some(child) = existing {
+            self.nodes.get_mut(&child).unwrap().location = location;
+            self.replace_state(child, state);
+            child
+        } else {
+            self.add_node(Some(parent), command, location, state)
+        };
+
+        self.nodes.get_mut(&parent).unwrap().active_child = Some(id);
+        self.current = Some(id);
+        self.enforce_budget();
+    }
+
+    // updates the current state without starting a new turn
+    pub fn replace_current(&mut self, location: String, state: State) {
+        match self.current {
+            Some(id) => {
+                self.nodes.get_mut(&id).unwrap().location = location;
+                self.replace_state(id, state);
+            }
+            None => {
+                let id = self.add_node(None, "", location, state);
+                self.current = Some(id);
+            }
+        }
+    }
+
+    pub fn undo(&mut self) -> Option<State> {
+        let current = self.current?;
+        let parent = self.nodes[&current].parent?;
+
+        self.nodes.get_mut(&parent).unwrap().active_child = Some(current);
+        self.current = Some(parent);
+
+        Some(self.materialize(parent))
+    }
+
+    pub fn redo(&mut self) -> Option<State> {
+        let current = self.current?;
+        let child = self.nodes[&current].active_child?;
+
+        self.current = Some(child);
+
+        Some(self.materialize(child))
+    }
+
+    // moves to any other state in the tree, making its branch the active one
+    pub fn switch(&mut self, id: usize) -> Option<State> {
+        if !self.nodes.contains_key(&id) {
+            return None;
+        }
+
+        let mut child = id;
+        while let Some(parent) = self.nodes[&child].parent {
+            self.nodes.get_mut(&parent).unwrap().active_child = Some(child);
+            child = parent;
+        }
+
+        self.current = Some(id);
+
+        Some(self.materialize(id))
+    }
+
+    // rebuilds a state from its chain of deltas
+    pub fn materialize(&mut self, id: usize) -> State {
+        let memory = self.memory_of(id);
+        let node = &self.nodes[&id];
+
+        let state = State {
+            pc: node.snapshot.pc,
+            frames: node.snapshot.frames.clone(),
+            memory: memory.clone(),
+        };
+
+        self.cache = Some((id, memory));
+        state
+    }
+
+    // finds a state by its number or label
+    pub fn find(&self, name: &str) -> Option<usize> {
+        let name = name.trim_start_matches('#');
+
+        if let Ok(id) = name.parse::<usize>() {
+            if self.nodes.contains_key(&id) {
+                return Some(id);
+            }
+        }
+
+        self.nodes
+            .values()
+            .find(|node| node.label.as_deref() == Some(name))
+            .map(|node| node.id)
+    }
+
+    pub fn label(&mut self, label: &str) -> bool {
+        match self.current {
+            Some(id) => {
+                self.nodes.get_mut(&id).unwrap().label = if label.is_empty() {
+                    None
+                } else {
+                    Some(String::from(label))
+                };
+                true
+            }
+            None => false,
+        }
+    }
+
+    // the active timeline: every state leading up to the current one, followed
+    // by the states that redo would move through
+    pub fn timeline(&self) -> (Vec<&HistoryNode>, usize) {
+        let current = match self.current {
+            Some(id) => id,
+            None => return (Vec::new(), 0),
+        };
+
+        let mut timeline = Vec::new();
+        let mut next = Some(current);
+
+        while let Some(id) = next {
+            timeline.push(&self.nodes[&id]);
+            next = self.nodes[&id].parent;
+        }
+
+        timeline.reverse();
+        let index = timeline.len() - 1;
+        let mut next = self.nodes[&current].active_child;
+
+        while let Some(id) = next {
+            timeline.push(&self.nodes[&id]);
+            next = self.nodes[&id].active_child;
+        }
+
+        (timeline, index)
+    }
+
+    // roughly how many bytes all of the stored states take up
+    pub fn size(&self) -> usize {
+        self.nodes.values().map(|node| node.snapshot.size()).sum()
+    }
+
+    pub fn tree_string(&self) -> String {
+        let mut out = String::new();
+
+        for node in self.nodes.values().filter(|node| node.parent.is_none()) {
+            self.print_tree(&mut out, node.id, "", 0, false);
+        }
+
+        out
+    }
+
+    fn print_tree(&self, out: &mut String, id: usize, indent: &str, depth: u8, is_last: bool) {
+        let node = &self.nodes[&id];
+        let marker = if self.current == Some(id) { " <-" } else { "" };
+        let mut next = String::from(indent);
+
+        if depth == 0 {
+            *out += &format!("{}{}\n", node.describe(), marker);
+        } else {
+            *out += &format!(
+                "{}{}── {}{}\n",
+                indent,
+                if is_last { "└" } else { "├" },
+                node.describe(),
+                marker
+            );
+
+            next += if is_last { "    " } else { "|   " };
+        }
+
+        for (i, child) in node.children.iter().enumerate() {
+            let is_last_child = i == node.children.len() - 1;
+            self.print_tree(out, *child, &next, depth.saturating_add(1), is_last_child);
+        }
+    }
+
+    // Gets the history ready to be written to disk. At most `limit` + 1 states
+    // are kept: the current one and the turns before it come first, then
+    // whatever else fits from the tree under the oldest of them (other
+    // branches, redo states). Each state gets rebuilt and turned into a save
+    // file with `export`.
+    pub fn to_entries<F>(&mut self, limit: usize, export: F) -> (Vec<HistoryEntry>, Option<usize>)
+    where
+        F: Fn(&State) -> Vec<u8>,
+    {
+        let mut top = match self.current {
+            Some(id) => id,
+            None => return (Vec::new(), None),
+        };
+
+        let mut path = HashSet::new();
+        path.insert(top);
+
+        for _ in 0..limit {
+            match self.nodes[&top].parent {
+                Some(parent) => top = parent,
+                None => break,
+            }
+
+            path.insert(top);
+        }
+
+        // parents need to come before their children (a node that doesn't
+        // fit takes its subtree with it)
+        let mut room = limit + 1 - path.len();
+        let mut order = vec![top];
+        let mut index = 0;
+
+        while index < order.len() {
+            let id = order[index];
+
+            for child in &self.nodes[&id].children {
+                if path.contains(child) {
+                    order.push(*child);
+                } else if room > 0 {
+                    room -= 1;
+                    order.push(*child);
+                }
+            }
+
+            index += 1;
+        }
+
+        let new_ids: HashMap<usize, usize> =
+            order.iter().enumerate().map(|(new, old)| (*old, new)).collect();
+
+        let entries = order
+            .iter()
+            .map(|id| {
+                let state = self.materialize(*id);
+                let node = &self.nodes[id];
+
+                HistoryEntry {
+                    parent: node.parent.and_then(|parent| new_ids.get(&parent).cloned()),
+                    command: node.command.clone(),
+                    location: node.location.clone(),
+                    label: node.label.clone(),
+                    state: export(&state),
+                }
+            })
+            .collect();
+
+        (entries, self.current.and_then(|id| new_ids.get(&id).cloned()))
+    }
+
+    // rebuilds a history from disk, with `import` turning each save back into
+    // a state (None if any of them can't be)
+    pub fn from_entries<F>(
+        entries: Vec<HistoryEntry>,
+        current: Option<usize>,
+        original: Vec<u8>,
+        budget: usize,
+        import: F,
+    ) -> Option<History>
+    where
+        F: Fn(&[u8]) -> Option<State>,
+    {
+        let mut history = History::new(original, budget);
+
+        for entry in entries {
+            let state = import(&entry.state)?;
+            let id = history.add_node(entry.parent, &entry.command, entry.location, state);
+            history.nodes.get_mut(&id).unwrap().label = entry.label;
+        }
+
+        if let Some(id) = current {
+            history.switch(id);
+        }
+
+        history.enforce_budget();
+        Some(history)
+    }
+
+    fn add_node(
+        &mut self,
+        parent: Option<usize>,
+        command: &str,
+        location: String,
+        state: State,
+    ) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let depth = parent.map_or(0, |parent| self.nodes[&parent].depth + 1);
+        let snapshot = self.compress(parent, depth, &state);
+
+        self.nodes.insert(
+            id,
+            HistoryNode {
+                id,
+                parent,
+                children: Vec::new(),
+                command: String::from(command),
+                location,
+                label: None,
+                depth,
+                snapshot,
+                active_child: None,
+            },
+        );
+
+        if let Some(parent) = parent {
+            self.nodes.get_mut(&parent).unwrap().children.push(id);
+        }
+
+        self.cache = Some((id, state.memory));
+        id
+    }
+
+    fn compress(&mut self, parent: Option<usize>, depth: usize, state: &State) -> Snapshot {
+        let memory = match parent {
+            Some(parent) if !depth.is_multiple_of(KEYFRAME_INTERVAL) => {
+                let base = self.memory_of(parent);
+                Memory::Delta(QuetzalSave::make_cmem_body(&state.memory, &base))
+            }
+            _ => Memory::Key(QuetzalSave::make_cmem_body(&state.memory, &self.original)),
+        };
+
+        Snapshot {
+            pc: state.pc,
+            frames: state.frames.clone(),
+            memory,
+        }
+    }
+
+    fn memory_of(&self, id: usize) -> Vec<u8> {
+        if let Some((cached, ref memory)) = self.cache {
+            if cached == id {
+                return memory.clone();
+            }
+        }
+
+        // walk back to the nearest keyframe, then apply each delta going forward
+        let mut chain = Vec::new();
+        let mut next = id;
+
+        loop {
+            let node = &self.nodes[&next];
+            chain.push(next);
+
+            match node.snapshot.memory {
+                Memory::Key(_) => break,
+                Memory::Delta(_) => next = node.parent.expect("Delta state without a parent!"),
+            }
+        }
+
+        chain.iter().rev().fold(Vec::new(), |memory, id| {
+            match self.nodes[id].snapshot.memory {
+                Memory::Key(ref bytes) => QuetzalSave::uncompress_memory(bytes, &self.original),
+                Memory::Delta(ref bytes) => QuetzalSave::uncompress_memory(bytes, &memory),
+            }
+        })
+    }
+
+    // swaps out the state of a node, re-compressing its children against it
+    fn replace_state(&mut self, id: usize, state: State) {
+        let children = self.nodes[&id].children.clone();
+        let child_memory: Vec<_> = children.iter().map(|child| self.memory_of(*child)).collect();
+
+        let (parent, depth) = {
+            let node = &self.nodes[&id];
+            (node.parent, node.depth)
+        };
+
+        let snapshot = self.compress(parent, depth, &state);
+        self.nodes.get_mut(&id).unwrap().snapshot = snapshot;
+
+        for (child, memory) in children.iter().zip(child_memory.iter()) {
+            if let Memory::Delta(_) = self.nodes[child].snapshot.memory {
+                let delta = QuetzalSave::make_cmem_body(memory, &state.memory);
+                self.nodes.get_mut(child).unwrap().snapshot.memory = Memory::Delta(delta);
+            }
+        }
+
+        self.cache = Some((id, state.memory));
+    }
+
+    // drops the oldest states until everything fits in the memory budget
+    // (the current state is always kept, even if it doesn't fit by itself)
+    fn enforce_budget(&mut self) {
+        while self.size() > self.budget {
+            let oldest = self.nodes
+                .values()
+                .find(|node| node.parent.is_none() && Some(node.id) != self.current)
+                .map(|node| node.id);
+
+            match oldest {
+                Some(id) => self.remove_root(id),
+                None => break,
+            }
+        }
+    }
+
+    fn remove_root(&mut self, id: usize) {
+        let children = self.nodes[&id].children.clone();
+
+        // children have to be stored against the original memory now
+        for child in &children {
+            let memory = self.memory_of(*child);
+            let node = self.nodes.get_mut(child).unwrap();
+
+            node.parent = None;
+            node.snapshot.memory = Memory::Key(QuetzalSave::make_cmem_body(&memory, &self.original));
+        }
+
+        self.nodes.remove(&id);
+
+        if let Some((cached, _)) = self.cache {
+            if cached == id {
+                self.cache = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{History, State, KEYFRAME_INTERVAL};
+
+    // a state for turn n, each one touching a different part of memory
+    fn state(n: usize) -> State {
+        let mut memory = vec![0; 256];
+        memory[n % 256] = n as u8 + 1;
+        memory[(n * 7) % 256] = 0xFF;
+
+        State {
+            pc: 0x1000 + n,
+            frames: vec![n as u8],
+            memory,
+        }
+    }
+
+    fn assert_state(actual: State, n: usize) {
+        let expected = state(n);
+
+        assert_eq!(actual.pc, expected.pc);
+        assert_eq!(actual.frames, expected.frames);
+        assert_eq!(actual.memory, expected.memory);
+    }
+
+    #[test]
+    fn undo_and_redo_across_keyframes() {
+        let turns = KEYFRAME_INTERVAL * 2 + 5;
+        let mut history = History::new(vec![0; 256], usize::MAX);
+
+        for n in 0..turns {
+            history.push(&format!("turn {}", n), String::new(), state(n));
+        }
+
+        for n in (0..turns - 1).rev() {
+            assert_state(history.undo().unwrap(), n);
+        }
+
+        assert!(history.undo().is_none());
+
+        for n in 1..turns {
+            assert_state(history.redo().unwrap(), n);
+        }
+
+        assert!(history.redo().is_none());
+
+        // (without the cache, straight from the deltas)
+        history.cache = None;
+        assert_state(history.materialize(KEYFRAME_INTERVAL + 3), KEYFRAME_INTERVAL + 3);
+    }
+
+    #[test]
+    fn new_command_after_undo_starts_a_branch() {
+        let mut history = History::new(vec![0; 256], usize::MAX);
+
+        history.push("", String::new(), state(0));
+        history.push("north", String::new(), state(1));
+        history.undo();
+        history.push("south", String::new(), state(2));
+
+        assert_eq!(history.nodes[&0].children, vec![1, 2]);
+        assert_state(history.undo().unwrap(), 0);
+        assert_state(history.redo().unwrap(), 2);
+        assert_state(history.switch(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn budget_drops_the_oldest_states() {
+        let mut history = History::new(vec![0; 256], usize::MAX);
+
+        for n in 0..10 {
+            history.push(&format!("turn {}", n), String::new(), state(n));
+        }
+
+        let budget = history.size() / 2;
+        history.budget = budget;
+
+        history.push("turn 10", String::new(), state(10));
+
+        assert!(history.size() <= budget);
+        assert!(!history.nodes.contains_key(&0));
+        assert_eq!(history.current, Some(10));
+
+        // the new root is stored on its own and everything left still rebuilds
+        let root = history.nodes.values().find(|node| node.parent.is_none()).unwrap().id;
+        history.cache = None;
+
+        for n in (root..10).rev() {
+            assert_state(history.undo().unwrap(), n);
+        }
+
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn budget_keeps_the_current_state() {
+        let mut history = History::new(vec![0; 256], 0);
+
+        history.push("", String::new(), state(0));
+        history.push("look", String::new(), state(1));
+
+        assert_eq!(history.nodes.len(), 1);
+        assert_eq!(history.current, Some(1));
+        assert_state(history.materialize(1), 1);
+    }
+
+    #[test]
+    fn entries_are_capped_in_total() {
+        let mut history = History::new(vec![0; 256], usize::MAX);
+
+        for n in 0..6 {
+            history.push(&format!("turn {}", n), String::new(), state(n));
+        }
+
+        // a redo branch off the first state, then back to the end
+        history.switch(0);
+        history.push("other", String::new(), state(6));
+        history.push("more", String::new(), state(7));
+        history.switch(5);
+
+        let export = |state: &State| vec![state.pc as u8];
+        let (entries, current) = history.to_entries(3, export);
+
+        assert_eq!(entries.len(), 4);
+        assert_eq!(current, Some(3));
+        assert_eq!(entries.iter().map(|e| e.state[0]).collect::<Vec<_>>(), vec![2, 3, 4, 5]);
+
+        let (entries, current) = history.to_entries(6, export);
+
+        // (room for one more: the first state of the other branch)
+        assert_eq!(entries.iter().map(|e| e.state[0]).collect::<Vec<_>>(), vec![0, 1, 6, 2, 3, 4, 5]);
+        assert_eq!(current, Some(6));
+        assert!(entries.iter().enumerate().all(|(i, e)| e.parent.map_or(true, |p| p < i)));
+    }
+}
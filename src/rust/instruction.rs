@@ -3,7 +3,7 @@ use std::hash;
 
 enum_from_primitive! {
     #[allow(non_camel_case_types)]
-    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
     pub enum Opcode {
         // Two-operand opcodes (2OP)
         OP2_1  = 1,  OP2_2  = 2,  OP2_3  = 3,  OP2_4  = 4,  OP2_5  = 5,  OP2_6  = 6,
@@ -318,6 +318,18 @@ impl Instruction {
         }
     }
 
+    // the first operand names a variable instead of being a value:
+    // inc, dec, load, store, pull, inc_chk and dec_chk
+    pub fn takes_variable(&self, version: u8) -> bool {
+        use self::Opcode::*;
+
+        match self.opcode {
+            OP2_4 | OP2_5 | OP2_13 | OP1_133 | OP1_134 | OP1_142 => true,
+            VAR_233 => version != 6,
+            _ => false,
+        }
+    }
+
     pub fn does_call(&self, version: u8) -> bool {
         use self::Opcode::*;
 
@@ -331,6 +343,18 @@ impl Instruction {
     pub fn should_advance(&self, version: u8) -> bool {
         !self.does_call(version) && self.opcode != Opcode::OP0_181 && self.opcode != Opcode::OP0_182
     }
+
+    // print_addr, print_obj, print_paddr, print, print_ret, new_line,
+    // print_char and print_num (for the watchdog, a game that's printing
+    // isn't stuck)
+    pub fn prints(&self) -> bool {
+        use self::Opcode::*;
+
+        matches!(
+            self.opcode,
+            OP1_135 | OP1_138 | OP1_141 | OP0_178 | OP0_179 | OP0_187 | VAR_229 | VAR_230
+        )
+    }
 }
 
 impl hash::Hash for Instruction {
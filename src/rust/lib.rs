@@ -0,0 +1,99 @@
+//! A z-machine interpreter for Infocom-era text adventure games.
+//!
+//! Load a story file with a `UI` for the text to go to, then run it until it
+//! needs something from the outside:
+//!
+//! ```no_run
+//! # use encrusted::{Event, Options, Zmachine, UI};
+//! # struct MyUI;
+//! # impl UI for MyUI {
+//! #     fn new() -> Box<MyUI> { Box::new(MyUI) }
+//! #     fn clear(&self) {}
+//! #     fn print(&mut self, _: &str) {}
+//! #     fn debug(&mut self, _: &str) {}
+//! #     fn print_object(&mut self, _: u16, _: &str) {}
+//! #     fn set_status_bar(&mut self, _: &str, _: &str) {}
+//! #     fn split_window(&mut self, _: u16) {}
+//! #     fn set_window(&mut self, _: u16) {}
+//! #     fn erase_window(&mut self, _: i16) {}
+//! #     fn erase_line(&mut self) {}
+//! #     fn set_cursor(&mut self, _: u16, _: u16) {}
+//! #     fn get_cursor(&self) -> (u16, u16) { (1, 1) }
+//! #     fn set_text_style(&mut self, _: u16) {}
+//! #     fn get_screen_size(&mut self) -> (usize, usize) { (80, 24) }
+//! #     fn reset(&mut self) {}
+//! #     fn get_user_input(&mut self) -> String { String::new() }
+//! #     fn get_user_char(&mut self) -> u16 { 13 }
+//! #     fn set_completions(&mut self, _: Vec<String>, _: usize) {}
+//! #     fn flush(&mut self) {}
+//! #     fn message(&self, _: &str, _: &str) {}
+//! # }
+//! # fn read_line() -> String { String::new() }
+//! # fn read_key() -> u16 { 13 }
+//! # fn write_file(_: &[u8]) -> bool { false }
+//! # fn read_file() -> Option<Vec<u8>> { None }
+//! # let story = Vec::new();
+//! let mut zvm = Zmachine::new(story, MyUI::new(), Options::default());
+//!
+//! loop {
+//!     match zvm.run_until_event() {
+//!         Event::LineInput => zvm.feed(&read_line()),
+//!         Event::CharInput => zvm.feed_char(read_key()),
+//!         Event::SaveRequested => {
+//!             let ok = write_file(&zvm.save_data());
+//!             zvm.finish_save(ok);
+//!         }
+//!         Event::RestoreRequested => zvm.finish_restore(read_file().as_ref().map(Vec::as_slice)),
+//!         Event::Quit => break,
+//!         // every so many instructions, or if it looks stuck in a loop
+//!         Event::Running => (),
+//!         Event::Stuck => { zvm.undo(); }
+//!         // at a breakpoint, debug commands go in through `feed`
+//!         Event::Break => zvm.feed("$continue"),
+//!     }
+//! }
+//! ```
+//!
+//! `snapshot`/`restore_snapshot` save and load the whole machine at any point,
+//! and `get_current_room`, `get_object_tree`, `get_surroundings` and
+//! `get_history` describe the game's state.
+
+#[cfg(target_arch = "wasm32")]
+extern crate js_sys;
+#[cfg(target_arch = "wasm32")]
+extern crate serde;
+#[cfg(target_arch = "wasm32")]
+extern crate serde_wasm_bindgen;
+#[cfg(target_arch = "wasm32")]
+extern crate wasm_bindgen;
+
+extern crate base64;
+extern crate rand;
+extern crate serde_json;
+
+#[macro_use]
+extern crate enum_primitive;
+
+#[macro_use]
+extern crate serde_derive;
+
+mod buffer;
+mod debuginfo;
+mod frame;
+mod history;
+mod instruction;
+mod options;
+mod profiler;
+mod quetzal;
+mod traits;
+mod zmachine;
+
+#[cfg(target_arch = "wasm32")]
+mod ui_web;
+#[cfg(target_arch = "wasm32")]
+mod web;
+
+pub use history::History;
+pub use options::Options;
+pub use traits::UI;
+pub use zmachine::{Event, Object, ObjectEntry, TreeUpdate, Zmachine};
@@ -0,0 +1,558 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use libc;
+use unicode_width::UnicodeWidthChar;
+
+// how many old commands are kept around (and read back from the history file)
+const HISTORY_LIMIT: usize = 1000;
+
+// how long to wait (ms) for the rest of an escape sequence
+const ESCAPE_TIMEOUT: libc::c_int = 50;
+
+#[derive(Debug, PartialEq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Tab,
+    Escape,
+    KillLine,
+    KillToEnd,
+    KillWord,
+    Search,
+    Cancel,
+    Interrupt,
+    EndOfFile,
+    Unknown,
+}
+
+// Puts the terminal in raw mode (no echo, no line buffering) for as long as
+// this is around, and puts it back the way it was when dropped.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> Option<RawMode> {
+        unsafe {
+            let mut original: libc::termios = mem::zeroed();
+
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return None;
+            }
+
+            // keep output processing on, so "\n" still moves to the next line
+            let mut raw = original;
+            raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+            raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::ISIG | libc::IEXTEN);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+
+            Some(RawMode { original })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+// A small readline-like editor for the terminal ui: arrow keys & the usual
+// emacs-style shortcuts, command recall (kept in a file per game), Ctrl-R to
+// search old commands and tab-completion of words in the game's dictionary.
+#[derive(Debug)]
+pub struct LineEditor {
+    history: Vec<String>,
+    path: Option<PathBuf>,
+    words: Vec<String>,
+    // how many letters of each word the dictionary keeps (a word that long
+    // might have been cut short)
+    resolution: usize,
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        LineEditor {
+            history: Vec::new(),
+            path: None,
+            words: Vec::new(),
+            resolution: 0,
+        }
+    }
+
+    pub fn set_completions(&mut self, mut words: Vec<String>, resolution: usize) {
+        words.retain(|word| word.chars().all(|c| c.is_alphanumeric() || c == '-'));
+        words.sort();
+        words.dedup();
+        self.words = words;
+        self.resolution = resolution;
+    }
+
+    // reads in old commands (and keeps adding new ones to the same file)
+    pub fn load_history(&mut self, path: &Path) {
+        if let Ok(file) = File::open(path) {
+            self.history = BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter(|line| !line.trim().is_empty())
+                .collect();
+
+            let excess = self.history.len().saturating_sub(HISTORY_LIMIT);
+            self.history.drain(..excess);
+        }
+
+        self.path = Some(path.to_path_buf());
+    }
+
+    fn add_history(&mut self, line: &str) {
+        if line.is_empty() || self.history.last().map(|last| last.as_str()) == Some(line) {
+            return;
+        }
+
+        self.history.push(String::from(line));
+
+        if self.history.len() > HISTORY_LIMIT {
+            self.history.remove(0);
+        }
+
+        // failing to write the history file shouldn't stop the game
+        if let Some(ref path) = self.path {
+            if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+                writeln!(file, "{}", line).ok();
+            }
+        }
+    }
+
+    // Reads a line with the cursor at `column` (where the prompt left off).
+    // Returns None if the terminal can't be used (or on Ctrl-C/Ctrl-D).
+    pub fn read_line(&mut self, column: usize) -> Option<String> {
+        let _raw = RawMode::enable()?;
+
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // position in the history while moving up/down (and the unfinished line)
+        let mut recall = self.history.len();
+        let mut draft: Vec<char> = Vec::new();
+        let mut last_key = Key::Unknown;
+
+        loop {
+            let key = read_key()?;
+
+            match key {
+                Key::Enter => break,
+                Key::Interrupt => return None,
+                Key::EndOfFile if line.is_empty() => return None,
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Delete | Key::EndOfFile if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::Left if cursor > 0 => cursor -= 1,
+                Key::Right if cursor < line.len() => cursor += 1,
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::KillLine => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                Key::KillToEnd => line.truncate(cursor),
+                Key::KillWord => {
+                    let start = word_start(&line, cursor);
+                    line.drain(start..cursor);
+                    cursor = start;
+                }
+                Key::Up if recall > 0 => {
+                    if recall == self.history.len() {
+                        draft = line.clone();
+                    }
+                    recall -= 1;
+                    line = self.history[recall].chars().collect();
+                    cursor = line.len();
+                }
+                Key::Down if recall < self.history.len() => {
+                    recall += 1;
+                    line = if recall == self.history.len() {
+                        draft.clone()
+                    } else {
+                        self.history[recall].chars().collect()
+                    };
+                    cursor = line.len();
+                }
+                Key::Tab => {
+                    let listed = last_key == Key::Tab;
+                    self.complete(&mut line, &mut cursor, column, listed);
+                }
+                Key::Search => {
+                    let (found, submit) = self.search(column)?;
+
+                    if let Some(found) = found {
+                        line = found.chars().collect();
+                        cursor = line.len();
+                    }
+
+                    if submit {
+                        redraw(column, &line, cursor);
+                        break;
+                    }
+                }
+                _ => (),
+            }
+
+            redraw(column, &line, cursor);
+            last_key = key;
+        }
+
+        println!();
+
+        let line: String = line.into_iter().collect();
+        self.add_history(line.trim());
+
+        Some(line)
+    }
+
+    // completes the word under the cursor as far as it can, listing all the
+    // possibilities if there's more than one (on the second tab)
+    fn complete(&self, line: &mut Vec<char>, cursor: &mut usize, column: usize, list: bool) {
+        let mut start = *cursor;
+        while start > 0 && line[start - 1] != ' ' {
+            start -= 1;
+        }
+
+        let prefix: String = line[start..*cursor].iter().collect::<String>().to_lowercase();
+
+        // past what the dictionary keeps, there's nothing more it can add
+        if prefix.is_empty() || prefix.chars().count() >= self.resolution {
+            return;
+        }
+
+        let matches: Vec<&String> = self.words.iter().filter(|w| w.starts_with(&prefix)).collect();
+
+        if matches.is_empty() {
+            return;
+        }
+
+        // longest prefix shared by every match
+        let mut common: Vec<char> = matches[0].chars().collect();
+        for word in &matches[1..] {
+            let same = common.iter().zip(word.chars()).take_while(|&(a, b)| *a == b).count();
+            common.truncate(same);
+        }
+
+        // a cut short word only gets filled in as far as it goes, the rest
+        // still has to be typed
+        if matches.len() == 1 && matches[0].chars().count() < self.resolution {
+            common.push(' ');
+        }
+
+        let added = common.len().saturating_sub(prefix.chars().count());
+        if added > 0 {
+            for (i, c) in common[common.len() - added..].iter().enumerate() {
+                line.insert(*cursor + i, *c);
+            }
+            *cursor += added;
+        } else if list && matches.len() > 1 {
+            let words = matches.iter().map(|w| w.as_str()).collect::<Vec<_>>().join("  ");
+            print!("\n{}\n{}", words, " ".repeat(column));
+        }
+    }
+
+    // Ctrl-R: searches back through the history as the query is typed.
+    // Another Ctrl-R finds an older match, Enter runs it, arrows take it to
+    // edit, and Esc cancels. Also returns if Enter was pressed.
+    fn search(&self, column: usize) -> Option<(Option<String>, bool)> {
+        let mut query = String::new();
+        let mut before = self.history.len();
+        let mut found: Option<usize> = None;
+
+        loop {
+            let shown = found.map_or("", |index| self.history[index].as_str());
+            let prompt = format!("(search)'{}': {}", query, shown);
+            let chars: Vec<char> = prompt.chars().collect();
+            redraw(column, &chars, chars.len());
+
+            match read_key()? {
+                Key::Char(c) => {
+                    query.push(c);
+                    before = self.history.len();
+                }
+                Key::Backspace => {
+                    query.pop();
+                    before = self.history.len();
+                }
+                Key::Search => {
+                    before = found.unwrap_or(before);
+                }
+                Key::Escape | Key::Cancel => return Some((None, false)),
+                Key::Interrupt => return None,
+                key => {
+                    let found = found.map(|index| self.history[index].clone());
+                    return Some((found, key == Key::Enter));
+                }
+            }
+
+            if query.is_empty() {
+                found = None;
+                continue;
+            }
+
+            found = self.history[..before]
+                .iter()
+                .rposition(|line| line.contains(&query))
+                .or(found);
+        }
+    }
+}
+
+// waits for any key (false if it was Ctrl-C or the input closed)
+pub fn wait_for_key() -> bool {
+    let _raw = match RawMode::enable() {
+        Some(raw) => raw,
+        None => return true,
+    };
+
+    match read_key() {
+        Some(Key::Interrupt) | None => false,
+        Some(_) => true,
+    }
+}
+
+// reads one keypress as a zscii code, None on Ctrl-C / Ctrl-D
+pub fn read_zscii_key() -> Option<u16> {
+    let _raw = RawMode::enable()?;
+
+    loop {
+        let code = match read_key()? {
+            Key::Enter => 13,
+            Key::Backspace | Key::Delete => 8,
+            Key::Escape => 27,
+            Key::Tab => 9,
+            Key::Up => 129,
+            Key::Down => 130,
+            Key::Left => 131,
+            Key::Right => 132,
+            Key::Char(c) if c.is_ascii() => c as u16,
+            Key::Interrupt | Key::EndOfFile => return None,
+            _ => continue,
+        };
+
+        return Some(code);
+    }
+}
+
+// start of the word that ends at the cursor
+fn word_start(line: &[char], cursor: usize) -> usize {
+    let mut start = cursor;
+
+    while start > 0 && line[start - 1] == ' ' {
+        start -= 1;
+    }
+    while start > 0 && line[start - 1] != ' ' {
+        start -= 1;
+    }
+
+    start
+}
+
+fn redraw(column: usize, line: &[char], cursor: usize) {
+    let text: String = line.iter().collect();
+    let offset: usize = line[..cursor].iter().map(|c| c.width().unwrap_or(0)).sum();
+
+    // Back to the start of the input: CR + ESC [nC
+    // then clear the rest of the line (ESC [K) and put the cursor back
+    let mut out = String::from("\r");
+    if column > 0 {
+        out.push_str(&format!("\x1B[{}C", column));
+    }
+    out.push_str(&text);
+    out.push_str("\x1B[K\r");
+    if column + offset > 0 {
+        out.push_str(&format!("\x1B[{}C", column + offset));
+    }
+
+    print!("{}", out);
+    io::stdout().flush().unwrap();
+}
+
+// like read_byte, but gives up if nothing comes in within `timeout` ms
+fn read_byte_within(fd: libc::c_int, timeout: libc::c_int) -> Option<u8> {
+    let mut fds = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    if unsafe { libc::poll(&mut fds, 1, timeout) } <= 0 {
+        return None;
+    }
+
+    read_byte(fd)
+}
+
+// straight from the fd: io::stdin() buffers, and would take the rest of an
+// escape sequence away from read_byte_within's poll
+fn read_byte(fd: libc::c_int) -> Option<u8> {
+    let mut buf = [0; 1];
+
+    loop {
+        match unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, 1) } {
+            1 => return Some(buf[0]),
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            _ => return None,
+        }
+    }
+}
+
+fn read_key() -> Option<Key> {
+    read_key_from(libc::STDIN_FILENO)
+}
+
+fn read_key_from(fd: libc::c_int) -> Option<Key> {
+    let byte = read_byte(fd)?;
+
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x7F | 0x08 => Key::Backspace,
+        b'\t' => Key::Tab,
+        0x01 => Key::Home,
+        0x02 => Key::Left,
+        0x03 => Key::Interrupt,
+        0x04 => Key::EndOfFile,
+        0x05 => Key::End,
+        0x06 => Key::Right,
+        0x07 => Key::Cancel,
+        0x0B => Key::KillToEnd,
+        0x0E => Key::Down,
+        0x10 => Key::Up,
+        0x12 => Key::Search,
+        0x15 => Key::KillLine,
+        0x17 => Key::KillWord,
+        0x1B => read_escape(fd),
+        0x00..=0x1F => Key::Unknown,
+        _ => Key::Char(read_utf8(fd, byte)?),
+    };
+
+    Some(key)
+}
+
+// arrow keys and friends: ESC [ A, ESC O H, ESC [ 3 ~, ...
+// a sequence comes in all at once, so an Esc with nothing right after it
+// was the Esc key itself
+fn read_escape(fd: libc::c_int) -> Key {
+    let kind = match read_byte_within(fd, ESCAPE_TIMEOUT) {
+        Some(kind) => kind,
+        None => return Key::Escape,
+    };
+
+    if kind != b'[' && kind != b'O' {
+        return Key::Unknown;
+    }
+
+    let mut code = match read_byte_within(fd, ESCAPE_TIMEOUT) {
+        Some(code) => code,
+        None => return Key::Unknown,
+    };
+    let mut number = 0;
+
+    while code.is_ascii_digit() || code == b';' {
+        if code.is_ascii_digit() {
+            number = number * 10 + u32::from(code - b'0');
+        }
+        code = match read_byte_within(fd, ESCAPE_TIMEOUT) {
+            Some(code) => code,
+            None => return Key::Unknown,
+        };
+    }
+
+    match (code, number) {
+        (b'A', _) => Key::Up,
+        (b'B', _) => Key::Down,
+        (b'C', _) => Key::Right,
+        (b'D', _) => Key::Left,
+        (b'H', _) | (b'~', 1) | (b'~', 7) => Key::Home,
+        (b'F', _) | (b'~', 4) | (b'~', 8) => Key::End,
+        (b'~', 3) => Key::Delete,
+        _ => Key::Unknown,
+    }
+}
+
+fn read_utf8(fd: libc::c_int, first: u8) -> Option<char> {
+    let length = match first {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    };
+
+    let mut bytes = vec![first];
+    for _ in 1..length {
+        bytes.push(read_byte(fd)?);
+    }
+
+    String::from_utf8(bytes).ok().and_then(|s| s.chars().next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_key_from, Key};
+    use libc;
+
+    // reads keys from a pipe with `input` already written into it
+    fn keys(input: &[u8]) -> Vec<Key> {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        let written = unsafe { libc::write(fds[1], input.as_ptr() as *const libc::c_void, input.len()) };
+        assert_eq!(written, input.len() as isize);
+        unsafe { libc::close(fds[1]) };
+
+        let mut keys = Vec::new();
+        while let Some(key) = read_key_from(fds[0]) {
+            keys.push(key);
+        }
+
+        unsafe { libc::close(fds[0]) };
+        keys
+    }
+
+    #[test]
+    fn test_escape_sequences() {
+        assert_eq!(keys(b"\x1B[A"), vec![Key::Up]);
+        assert_eq!(keys(b"\x1B[3~x"), vec![Key::Delete, Key::Char('x')]);
+        assert_eq!(keys(b"\x1BOH\x1B[1;5D"), vec![Key::Home, Key::Left]);
+    }
+
+    #[test]
+    fn test_lone_escape() {
+        assert_eq!(keys(b"\x1B"), vec![Key::Escape]);
+    }
+
+    #[test]
+    fn test_utf8() {
+        assert_eq!(keys("é\r".as_bytes()), vec![Key::Char('é'), Key::Enter]);
+    }
+}
@@ -1,40 +1,42 @@
 extern crate atty;
 extern crate base64;
 extern crate clap;
+extern crate encrusted;
+extern crate libc;
 extern crate rand;
 extern crate regex;
-extern crate serde_json;
 extern crate term_size;
+extern crate unicode_width;
 
 #[macro_use]
 extern crate lazy_static;
 
 #[macro_use]
-extern crate enum_primitive;
-
-#[macro_use]
-extern crate serde_derive;
+extern crate serde_json;
 
+use std::env;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
+use std::panic;
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
 use std::process;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde_json::Value;
 
-mod buffer;
-mod frame;
-mod instruction;
-mod options;
-mod quetzal;
-mod traits;
+mod line_editor;
+mod theme;
+mod ui_json;
+mod ui_remglk;
 mod ui_terminal;
-mod zmachine;
 
-use options::Options;
-use traits::UI;
+use encrusted::{Event, Options, Zmachine, UI};
+use theme::Theme;
+use ui_json::JsonUI;
+use ui_remglk::RemGlkUI;
 use ui_terminal::TerminalUI;
-use zmachine::Zmachine;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -42,15 +44,149 @@ fn main() {
     let matches = App::new("encrusted")
         .version(VERSION)
         .about("A zmachine interpreter")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("FILE")
                 .help("Sets the story file to run")
                 .required(true),
         )
+        .arg(
+            Arg::with_name("fullscreen")
+                .short("f")
+                .long("fullscreen")
+                .help("Uses the whole terminal, with the status line at the top"),
+        )
+        .arg(
+            Arg::with_name("theme")
+                .long("theme")
+                .value_name("NAME|FILE")
+                .help("Sets the colors: default, light, dark, none, or a theme file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("width")
+                .long("width")
+                .value_name("COLUMNS")
+                .help("Sets the screen width instead of using the terminal's")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rem")
+                .long("rem")
+                .help("Talks the RemGlk JSON protocol on stdin/stdout instead")
+                .conflicts_with("fullscreen"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Prints a JSON object each turn and reads JSON commands")
+                .conflicts_with_all(&["fullscreen", "rem"]),
+        )
+        .arg(
+            Arg::with_name("undo-limit")
+                .long("undo-limit")
+                .value_name("TURNS")
+                .help("Sets how many undo turns get saved next to save files")
+                .takes_value(true),
+        )
+        .arg(debug_file_arg())
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("FILE")
+                .help("Profiles the game and writes the results to FILE at quit")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Prints a listing of the story file's routines")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("Sets the story file to disassemble")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("routine")
+                        .long("routine")
+                        .value_name("ADDR")
+                        .help("Only lists the routine at this address (hex)")
+                        .takes_value(true)
+                        .conflicts_with_all(&["from", "to"]),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("ADDR")
+                        .help("Only lists routines from this address on (hex)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("ADDR")
+                        .help("Only lists routines up to this address (hex)")
+                        .takes_value(true),
+                )
+                .arg(debug_file_arg()),
+        )
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("disasm") {
+        disassemble(matches);
+        return;
+    }
+
     let path = Path::new(matches.value_of("FILE").unwrap());
+    let data = read_story(path);
+
+    let json = matches.is_present("json");
 
+    let ui: Box<dyn UI> = if json {
+        JsonUI::new()
+    } else if matches.is_present("rem") {
+        RemGlkUI::new()
+    } else {
+        terminal_ui(&matches, path)
+    };
+
+    let mut opts = Options::default();
+    opts.save_dir = path.parent().unwrap().to_string_lossy().into_owned();
+    opts.save_name = path.file_stem().unwrap().to_string_lossy().into_owned();
+
+    if let Some(limit) = matches.value_of("undo-limit") {
+        opts.undo_limit = limit.parse().unwrap_or_else(|_| {
+            println!("\nInvalid undo limit: {}\n", limit);
+            process::exit(1);
+        });
+    }
+
+    if let Some(file) = matches.value_of("profile") {
+        opts.profile_file = file.to_string();
+    }
+
+    let rand32 = || rand::random();
+    opts.rand_seed = [rand32(), rand32(), rand32(), rand32()];
+
+    let mut zvm = Zmachine::new(data, ui, opts);
+    load_debug_file(&mut zvm, &matches);
+
+    // the panic message gets printed by the hook, then where the game was
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        if json {
+            run_json(&mut zvm);
+        } else {
+            zvm.run();
+        }
+    }));
+
+    if result.is_err() {
+        eprintln!("\nZ-machine backtrace:\n{}", zvm.backtrace());
+        process::exit(101);
+    }
+}
+
+// the story file, or exits if it's missing or doesn't look like one
+fn read_story(path: &Path) -> Vec<u8> {
     if !path.is_file() {
         println!(
             "\nCouldn't find game file: \n   {}\n",
@@ -76,16 +212,257 @@ fn main() {
         process::exit(1);
     }
 
-    let ui = TerminalUI::new();
+    data
+}
 
-    let mut opts = Options::default();
-    opts.save_dir = path.parent().unwrap().to_string_lossy().into_owned();
-    opts.save_name = path.file_stem().unwrap().to_string_lossy().into_owned();
+// (for the game and for disasm)
+fn debug_file_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("debug-file")
+        .long("debug-file")
+        .value_name("FILE")
+        .help("Loads names from an Inform debug file (gameinfo.dbg) for the $ commands")
+        .takes_value(true)
+}
 
-    let rand32 = || rand::random();
-    opts.rand_seed = [rand32(), rand32(), rand32(), rand32()];
+// names for routines, globals, objects, attributes and properties
+fn load_debug_file(zvm: &mut Zmachine, matches: &ArgMatches) {
+    let path = match matches.value_of("debug-file") {
+        Some(path) => Path::new(path),
+        None => return,
+    };
 
-    let mut zvm = Zmachine::new(data, ui, opts);
+    let mut data = Vec::new();
+    let result = File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|err| format!("Couldn't read debug file {}: {}", path.to_string_lossy(), err))
+        .and_then(|_| zvm.load_debug_info(&data));
+
+    if let Err(err) = result {
+        println!("\n{}\n", err);
+        process::exit(1);
+    }
+}
+
+// disasm: every routine, the ones in a range, or just one
+fn disassemble(matches: &ArgMatches) {
+    let path = Path::new(matches.value_of("FILE").unwrap());
+    let mut zvm = Zmachine::disassembler(read_story(path));
+    load_debug_file(&mut zvm, matches);
+
+    let address = |name: &str, default: usize| match matches.value_of(name) {
+        Some(value) => usize::from_str_radix(value.trim_start_matches("0x"), 16).unwrap_or_else(|_| {
+            println!("\nInvalid address (in hex): {}\n", value);
+            process::exit(1);
+        }),
+        None => default,
+    };
+
+    if matches.is_present("routine") {
+        let addr = address("routine", 0);
+
+        match zvm.disassemble_routine(addr) {
+            Some(listing) => print_listing(&listing),
+            None => {
+                println!("\nNo routine at {:x}\n", addr);
+                process::exit(1);
+            }
+        }
+    } else {
+        let listing = zvm.disassemble(address("from", 0), address("to", usize::MAX));
+        print_listing(&listing);
+    }
+}
+
+// (quietly, so piping it into head or less doesn't panic when they close)
+fn print_listing(listing: &str) {
+    io::stdout().write_all(listing.as_bytes()).ok();
+}
+
+fn terminal_ui(matches: &ArgMatches, path: &Path) -> Box<TerminalUI> {
+    let mut ui = if matches.is_present("fullscreen") {
+        // put the terminal back to normal if something goes wrong
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            print!("\x1B[0m\x1B[r\x1B[?1049l");
+            default_hook(info);
+        }));
+
+        TerminalUI::fullscreen()
+    } else {
+        TerminalUI::new()
+    };
+
+    if let Some(width) = matches.value_of("width") {
+        match width.parse() {
+            Ok(width) if width > 0 => ui.set_width(width),
+            _ => {
+                println!("\nInvalid width: {}\n", width);
+                process::exit(1);
+            }
+        }
+    }
+
+    // a theme on the command line wins, otherwise check the config file
+    let theme = if let Some(name) = matches.value_of("theme") {
+        Theme::named(name).map_or_else(|| Theme::from_file(Path::new(name)), Ok)
+    } else {
+        let theme = match Theme::config_path() {
+            Some(ref config) if config.is_file() => Theme::from_file(config),
+            _ => Ok(Theme::default()),
+        };
+
+        // https://no-color.org
+        match env::var_os("NO_COLOR") {
+            Some(ref value) if !value.is_empty() => theme.map(|theme| theme.without_colors()),
+            _ => theme,
+        }
+    };
+
+    match theme {
+        Ok(theme) => ui.set_theme(theme),
+        Err(err) => {
+            println!("\n{}\n", err);
+            process::exit(1);
+        }
+    }
+
+    // command history for the line editor (ex: "zork.z3" => "zork.history")
+    ui.load_history(&path.with_extension("history"));
+
+    ui
+}
+
+// --json: runs like the web ui does (pausing at each read), with one JSON
+// object out per turn and one command in per line:
+//
+//     "open mailbox"  or  {"command": "open mailbox"}
+//     {"meta": "undo"}  {"meta": "redo"}
+//     {"meta": "save", "file": "zork.state"}  (no file: gives base64 "savestate")
+//     {"meta": "restore", "file": "zork.state"}  (or "data": base64)
+//
+// (the last object, at quit, has a "savestate" from before the quit command)
+// (at a breakpoint "input" is "debug", and only $ commands are taken)
+//
+fn run_json(zvm: &mut Zmachine) {
+    let stdin = io::stdin();
+    let mut event = step_json(zvm);
+
+    loop {
+        send_turn(zvm, event);
+
+        if event == Event::Quit {
+            break;
+        }
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+
+        let result = serde_json::from_str(line.trim())
+            .map_err(|err| format!("Invalid JSON: {}", err))
+            .and_then(|command| handle_json_command(zvm, &command));
+
+        // errors get reported without running anything
+        if let Err(err) = result {
+            zvm.ui.message("error", &json!(err).to_string());
+            continue;
+        }
+
+        event = step_json(zvm);
+    }
+}
+
+// runs the whole turn (unless it looks stuck)
+fn step_json(zvm: &mut Zmachine) -> Event {
+    loop {
+        match zvm.step() {
+            Event::Running => continue,
+            event => return event,
+        }
+    }
+}
+
+fn send_turn(zvm: &mut Zmachine, event: Event) {
+    let (number, name) = zvm.get_current_room();
+    let (inventory, objects) = zvm.get_surroundings();
+    let input = if event == Event::Quit { None } else { zvm.waiting_for() };
+
+    if event == Event::Stuck {
+        zvm.ui.message("stuck", "true");
+    }
+
+    zvm.update_status_bar();
+    zvm.ui.message("room", &json!({ "number": number, "name": name }).to_string());
+    zvm.ui.message("inventory", &json!(inventory).to_string());
+    zvm.ui.message("objects", &json!(objects).to_string());
+    zvm.ui.message("input", &json!(input).to_string());
+    zvm.ui.flush();
+}
+
+fn handle_json_command(zvm: &mut Zmachine, command: &Value) -> Result<(), String> {
+    let text = command.as_str().or_else(|| command["command"].as_str());
+    let restoring = zvm.waiting_for() == Some("restore");
+    let stuck = zvm.paused_event().is_none();
+
+    match (command["meta"].as_str(), text) {
+        (Some("undo"), _) => {
+            let ok = zvm.undo();
+            zvm.ui.message("undo", &ok.to_string());
+        }
+        (Some("redo"), _) => {
+            let ok = zvm.redo();
+            zvm.ui.message("redo", &ok.to_string());
+        }
+        (Some("save"), _) => {
+            let data = zvm.get_savestate().ok_or("Nothing to save yet")?;
+
+            if let Some(file) = command["file"].as_str() {
+                File::create(file)
+                    .and_then(|mut handle| handle.write_all(&data))
+                    .map_err(|err| format!("Couldn't save to {}: {}", file, err))?;
+
+                zvm.ui.message("saved", &json!(file).to_string());
+            } else {
+                let msg = json!(["", base64::encode(&data)]).to_string();
+                zvm.ui.message("savestate", &msg);
+            }
+        }
+        (Some("restore"), _) => {
+            let data = if let Some(file) = command["file"].as_str() {
+                let mut data = Vec::new();
+
+                File::open(file)
+                    .and_then(|mut handle| handle.read_to_end(&mut data))
+                    .map_err(|err| format!("Couldn't restore from {}: {}", file, err))?;
+
+                base64::encode(&data)
+            } else {
+                let data = command["data"].as_str().ok_or("Restore needs a file or data")?;
+                String::from(data)
+            };
+
+            // the game's own restore takes its own saves, otherwise it's a
+            // savestate from a meta save (a bad one is reported, and changes
+            // nothing)
+            if restoring {
+                zvm.restore(&data)?;
+            } else {
+                zvm.load_savestate(&data)?;
+            }
+        }
+        (Some("continue"), _) if stuck => (),
+        (Some(meta), _) => return Err(format!("Unknown meta command: {}", meta)),
+        (None, Some(_)) if stuck => {
+            return Err(String::from(
+                "The game looks stuck in a loop, send {\"meta\": \"undo\"} or {\"meta\": \"continue\"}",
+            ))
+        }
+        // anything else cancels the game's restore
+        (None, Some(_)) if restoring => zvm.restore("")?,
+        (None, Some(text)) => zvm.handle_input(String::from(text)),
+        (None, None) => return Err(String::from("Expected a command")),
+    }
 
-    zvm.run();
+    Ok(())
 }
@@ -3,7 +3,16 @@ pub struct Options {
     pub save_dir: String,
     pub save_name: String,
     pub log_instructions: bool,
+    pub undo_limit: usize,
+    pub undo_budget: usize,
     pub rand_seed: [u32; 4],
+    // instructions per run_until_event call before yielding (0 for no limit)
+    pub instruction_budget: usize,
+    // instructions without output or input before calling the game stuck
+    pub watchdog_limit: usize,
+    // profile the game from the start and write the results here at quit
+    // (empty for no profiling)
+    pub profile_file: String,
 }
 
 impl Options {
@@ -12,7 +21,12 @@ impl Options {
             save_dir: String::new(),
             save_name: String::new(),
             log_instructions: false,
+            undo_limit: 100,
+            undo_budget: 4 * 1024 * 1024,
             rand_seed: [90, 111, 114, 107],
+            instruction_budget: 100_000,
+            watchdog_limit: 10_000_000,
+            profile_file: String::new(),
         }
     }
 }
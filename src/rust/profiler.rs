@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+use instruction::{Instruction, Opcode};
+
+// Counts what the game does: every opcode run, and per routine the cost of
+// running it (exclusive) and of it plus everything it called (inclusive).
+// Costs are in instructions, which come out the same on a fast machine as on
+// the slow one a profile is meant to help, and in wall-clock time, which also
+// catches slow I/O. The clock only runs while the game does, not while it
+// waits for input (and there's no clock in the browser, only instructions)
+pub struct Profile {
+    version: u8,
+    instructions: u64,
+    opcodes: HashMap<Opcode, u64>,
+    routines: HashMap<usize, RoutineCost>,
+    // (caller, callee)
+    calls: HashMap<(usize, usize), CallCost>,
+    stack: Vec<Running>,
+    max_depth: usize,
+    // instructions and time in each finished turn (from one input to the next)
+    turns: Vec<(u64, u64)>,
+    turn_start: (u64, u64),
+    clock: Clock,
+}
+
+// a frame's routine, and what had been spent when it started
+struct Running {
+    routine: usize,
+    instructions: u64,
+    time: u64,
+    // time spent in the routines it called
+    callees: u64,
+}
+
+#[derive(Default)]
+struct RoutineCost {
+    calls: u64,
+    exclusive: u64,
+    inclusive: u64,
+    // (in microseconds)
+    exclusive_time: u64,
+    inclusive_time: u64,
+}
+
+#[derive(Default)]
+struct CallCost {
+    calls: u64,
+    inclusive: u64,
+    inclusive_time: u64,
+}
+
+// Microseconds the game has been running for, leaving out the pauses
+struct Clock {
+    #[cfg(not(target_arch = "wasm32"))]
+    start: Instant,
+    idle: u64,
+    paused_at: Option<u64>,
+}
+
+impl Clock {
+    fn new() -> Clock {
+        Clock {
+            #[cfg(not(target_arch = "wasm32"))]
+            start: Instant::now(),
+            idle: 0,
+            paused_at: Some(0),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn available() -> bool {
+        true
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn available() -> bool {
+        false
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn now(&self) -> u64 {
+        0
+    }
+
+    fn time(&self) -> u64 {
+        self.paused_at.unwrap_or_else(|| self.now()) - self.idle
+    }
+
+    fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(self.now());
+        }
+    }
+
+    fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.idle += self.now() - paused_at;
+        }
+    }
+}
+
+impl Running {
+    fn new(routine: usize, instructions: u64, time: u64) -> Running {
+        Running {
+            routine,
+            instructions,
+            time,
+            callees: 0,
+        }
+    }
+}
+
+// (microseconds as milliseconds)
+fn ms(time: u64) -> f64 {
+    time as f64 / 1000.0
+}
+
+impl Profile {
+    // starts with the routines already running (0 for one that isn't known),
+    // and the clock stopped until the game runs
+    pub fn new(version: u8, routines: &[usize]) -> Profile {
+        Profile {
+            version,
+            instructions: 0,
+            opcodes: HashMap::new(),
+            routines: HashMap::new(),
+            calls: HashMap::new(),
+            stack: routines.iter().map(|routine| Running::new(*routine, 0, 0)).collect(),
+            max_depth: routines.len(),
+            turns: Vec::new(),
+            turn_start: (0, 0),
+            clock: Clock::new(),
+        }
+    }
+
+    // when the game starts running, and when it stops for anything (input,
+    // a save, a breakpoint, handing control back to the front end)
+    pub fn resume(&mut self) {
+        self.clock.resume();
+    }
+
+    pub fn pause(&mut self) {
+        self.clock.pause();
+    }
+
+    // before each instruction runs, with the number of frames then. the stack
+    // catches up with anything that replaced the frames (restore, undo...)
+    pub fn instruction(&mut self, opcode: Opcode, depth: usize) {
+        self.returned(depth);
+
+        while self.stack.len() < depth {
+            let time = self.clock.time();
+            self.stack.push(Running::new(0, self.instructions, time));
+        }
+
+        self.instructions += 1;
+        *self.opcodes.entry(opcode).or_insert(0) += 1;
+
+        let routine = self.stack.last().map_or(0, |running| running.routine);
+        self.routines.entry(routine).or_default().exclusive += 1;
+    }
+
+    // with the number of frames after the call
+    pub fn called(&mut self, routine: usize, depth: usize) {
+        let caller = self.stack.last().map_or(0, |running| running.routine);
+
+        self.routines.entry(routine).or_default().calls += 1;
+        self.calls.entry((caller, routine)).or_default().calls += 1;
+
+        let time = self.clock.time();
+        self.stack.push(Running::new(routine, self.instructions, time));
+        self.max_depth = self.max_depth.max(depth);
+    }
+
+    // with the number of frames left after returning
+    pub fn returned(&mut self, depth: usize) {
+        while self.stack.len() > depth {
+            let running = self.stack.pop().unwrap();
+            let routine = running.routine;
+            let time = self.clock.time() - running.time;
+
+            // exclusive time is the frame's own, recursive or not
+            self.routines.entry(routine).or_default().exclusive_time += time - running.callees;
+
+            let caller = match self.stack.last_mut() {
+                Some(caller) => {
+                    caller.callees += time;
+                    caller.routine
+                }
+                None => 0,
+            };
+
+            // (a recursive call is already counted by the outer one)
+            if self.stack.iter().any(|outer| outer.routine == routine) {
+                continue;
+            }
+
+            let cost = self.instructions - running.instructions;
+
+            let totals = self.routines.entry(routine).or_default();
+            totals.inclusive += cost;
+            totals.inclusive_time += time;
+
+            let call = self.calls.entry((caller, routine)).or_default();
+            call.inclusive += cost;
+            call.inclusive_time += time;
+        }
+    }
+
+    // at each input
+    pub fn turn(&mut self) {
+        let now = (self.instructions, self.clock.time());
+
+        self.turns.push((now.0 - self.turn_start.0, now.1 - self.turn_start.1));
+        self.turn_start = now;
+    }
+
+    // a flat profile (routines by exclusive instructions), a call graph (by
+    // inclusive instructions) and the opcode counts
+    pub fn report<F: Fn(usize) -> String>(&self, label: F) -> String {
+        let mut out = String::new();
+        let total = self.instructions.max(1) as f64;
+        let percent = |count: u64| count as f64 * 100.0 / total;
+
+        // (routines still running haven't been added to their inclusive
+        // costs yet)
+        let now = self.clock.time();
+
+        let mut inclusive = self
+            .routines
+            .iter()
+            .map(|(addr, cost)| (*addr, (cost.inclusive, cost.inclusive_time)))
+            .collect::<HashMap<_, _>>();
+        let mut exclusive_time = self
+            .routines
+            .iter()
+            .map(|(addr, cost)| (*addr, cost.exclusive_time))
+            .collect::<HashMap<_, _>>();
+        let mut call_inclusive = self
+            .calls
+            .iter()
+            .map(|(call, cost)| (*call, (cost.inclusive, cost.inclusive_time)))
+            .collect::<HashMap<_, _>>();
+
+        for (index, running) in self.stack.iter().enumerate() {
+            let routine = running.routine;
+            let cost = self.instructions - running.instructions;
+            let time = now - running.time;
+            let callee_time = self.stack.get(index + 1).map_or(0, |callee| now - callee.time);
+
+            *exclusive_time.entry(routine).or_insert(0) += time - running.callees - callee_time;
+
+            if self.stack[..index].iter().any(|outer| outer.routine == routine) {
+                continue;
+            }
+
+            let totals = inclusive.entry(routine).or_insert((0, 0));
+            totals.0 += cost;
+            totals.1 += time;
+
+            if index > 0 {
+                let caller = self.stack[index - 1].routine;
+                let call = call_inclusive.entry((caller, routine)).or_insert((0, 0));
+                call.0 += cost;
+                call.1 += time;
+            }
+        }
+
+        writeln!(out, "Instructions: {}", self.instructions).unwrap();
+
+        if Clock::available() {
+            writeln!(out, "Time running: {:.1} ms", ms(now)).unwrap();
+        } else {
+            writeln!(out, "Time running: no clock here, so times are all 0").unwrap();
+        }
+
+        writeln!(out, "Deepest call stack: {} frames", self.max_depth).unwrap();
+
+        if !self.turns.is_empty() {
+            let count = self.turns.len() as u64;
+            let (most, instructions) = self
+                .turns
+                .iter()
+                .enumerate()
+                .max_by_key(|&(_, turn)| turn.0)
+                .unwrap();
+            let (slowest, time) = self
+                .turns
+                .iter()
+                .enumerate()
+                .max_by_key(|&(_, turn)| turn.1)
+                .unwrap();
+
+            writeln!(
+                out,
+                "Turns: {}, {} instructions per turn on average, most in turn {} ({})",
+                count,
+                self.turns.iter().map(|turn| turn.0).sum::<u64>() / count,
+                most + 1,
+                instructions.0
+            ).unwrap();
+            writeln!(
+                out,
+                "       {:.1} ms per turn on average, slowest turn {} ({:.1} ms)",
+                ms(self.turns.iter().map(|turn| turn.1).sum::<u64>() / count),
+                slowest + 1,
+                ms(time.1)
+            ).unwrap();
+        }
+
+        let mut routines = self.routines.iter().collect::<Vec<_>>();
+        routines.sort_by(|a, b| b.1.exclusive.cmp(&a.1.exclusive).then(a.0.cmp(b.0)));
+
+        writeln!(out, "\nFlat profile:\n").unwrap();
+        writeln!(
+            out,
+            "    exclusive        inclusive        calls   exclusive ms   inclusive ms  routine"
+        ).unwrap();
+
+        for (addr, cost) in &routines {
+            let (inclusive, inclusive_time) = inclusive[addr];
+
+            writeln!(
+                out,
+                "{:10} {:5.1}% {:10} {:5.1}% {:8} {:14.1} {:14.1}  {}",
+                cost.exclusive,
+                percent(cost.exclusive),
+                inclusive,
+                percent(inclusive),
+                cost.calls,
+                ms(exclusive_time[addr]),
+                ms(inclusive_time),
+                label(**addr)
+            ).unwrap();
+        }
+
+        routines.sort_by(|a, b| inclusive[b.0].0.cmp(&inclusive[a.0].0).then(a.0.cmp(b.0)));
+
+        writeln!(out, "\nCall graph:").unwrap();
+
+        for (addr, cost) in &routines {
+            writeln!(
+                out,
+                "\n{} ({} inclusive, {} exclusive, {} calls, {:.1} ms inclusive)",
+                label(**addr),
+                inclusive[addr].0,
+                cost.exclusive,
+                cost.calls,
+                ms(inclusive[addr].1)
+            ).unwrap();
+
+            let mut callers = self
+                .calls
+                .iter()
+                .filter(|&(&(_, to), call)| to == **addr && call.calls > 0)
+                .collect::<Vec<_>>();
+            callers.sort_by(|a, b| b.1.calls.cmp(&a.1.calls).then((a.0).0.cmp(&(b.0).0)));
+
+            for (&(from, _), call) in callers {
+                writeln!(out, "    called by {:<24} {:8} calls", label(from), call.calls).unwrap();
+            }
+
+            let mut callees = call_inclusive
+                .iter()
+                .filter(|&(&(from, _), _)| from == **addr)
+                .collect::<Vec<_>>();
+            callees.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then((a.0).1.cmp(&(b.0).1)));
+
+            for (&(from, to), &(cost, time)) in callees {
+                writeln!(
+                    out,
+                    "    calls     {:<24} {:8} calls {:10} instructions {:10.1} ms",
+                    label(to),
+                    self.calls.get(&(from, to)).map_or(0, |call| call.calls),
+                    cost,
+                    ms(time)
+                ).unwrap();
+            }
+        }
+
+        let mut opcodes = self.opcodes.iter().collect::<Vec<_>>();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1));
+
+        writeln!(out, "\nOpcodes:\n").unwrap();
+
+        for (opcode, count) in opcodes {
+            let name = Instruction::name(*opcode, self.version);
+            writeln!(out, "{:10} {:5.1}%  {}", count, percent(*count), name).unwrap();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profile;
+    use instruction::Opcode;
+    use std::thread;
+    use std::time::Duration;
+
+    const A: usize = 0x100;
+    const B: usize = 0x200;
+
+    // runs `count` instructions with `depth` frames
+    fn run(profile: &mut Profile, count: usize, depth: usize) {
+        for _ in 0..count {
+            profile.instruction(Opcode::OP0_180, depth);
+        }
+    }
+
+    #[test]
+    fn test_recursion_counts_inclusive_once() {
+        let mut profile = Profile::new(3, &[0]);
+
+        run(&mut profile, 1, 1);
+        profile.called(A, 2);
+        run(&mut profile, 3, 2);
+        profile.called(A, 3);
+        run(&mut profile, 2, 3);
+        // returns from the inner A, then the outer one
+        run(&mut profile, 1, 2);
+        run(&mut profile, 1, 1);
+
+        let a = &profile.routines[&A];
+        assert_eq!(a.calls, 2);
+        assert_eq!(a.exclusive, 6);
+        assert_eq!(a.inclusive, 6);
+        assert!(a.exclusive_time <= a.inclusive_time);
+
+        assert_eq!(profile.calls[&(0, A)].inclusive, 6);
+        assert_eq!(profile.calls[&(A, A)].calls, 1);
+        assert_eq!(profile.calls[&(A, A)].inclusive, 0);
+        assert_eq!(profile.routines[&0].exclusive, 2);
+        assert_eq!(profile.max_depth, 3);
+    }
+
+    #[test]
+    fn test_mutual_recursion() {
+        let mut profile = Profile::new(3, &[0]);
+
+        profile.called(A, 2);
+        run(&mut profile, 1, 2);
+        profile.called(B, 3);
+        run(&mut profile, 1, 3);
+        profile.called(A, 4);
+        run(&mut profile, 2, 4);
+        // returns all the way back to the main routine at once
+        profile.returned(1);
+
+        assert_eq!(profile.routines[&A].exclusive, 3);
+        assert_eq!(profile.routines[&A].inclusive, 4);
+        assert_eq!(profile.routines[&B].exclusive, 1);
+        assert_eq!(profile.routines[&B].inclusive, 3);
+        assert_eq!(profile.calls[&(A, B)].inclusive, 3);
+        assert_eq!(profile.calls[&(B, A)].inclusive, 0);
+        assert!(profile.stack.len() == 1);
+    }
+
+    #[test]
+    fn test_running_routines_in_report() {
+        let mut profile = Profile::new(3, &[0]);
+        let label = |addr: usize| format!("r{:x}", addr);
+
+        run(&mut profile, 1, 1);
+        profile.called(A, 2);
+        run(&mut profile, 4, 2);
+
+        let report = profile.report(label);
+        assert!(report.contains("Instructions: 5"));
+        assert!(report.contains("r100 (4 inclusive, 4 exclusive, 1 calls"));
+        assert!(report.contains("r0 (5 inclusive, 1 exclusive, 0 calls"));
+    }
+
+    #[test]
+    fn test_clock_stops_while_paused() {
+        let mut profile = Profile::new(3, &[0]);
+        assert_eq!(profile.clock.time(), 0);
+
+        profile.resume();
+        profile.pause();
+        let time = profile.clock.time();
+
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(profile.clock.time(), time);
+        profile.turn();
+        assert_eq!(profile.turns, vec![(0, time)]);
+
+        profile.resume();
+        assert!(profile.clock.idle >= 5000);
+    }
+}
@@ -19,44 +19,50 @@ impl QuetzalSave {
         }
     }
 
-    pub fn from_bytes(save_data: &[u8], original_dynamic: &[u8]) -> QuetzalSave {
+    // reads a save, giving up on anything unexpected instead of panicking
+    // (a damaged save or undo history shouldn't take the game down)
+    pub fn try_from_bytes(save_data: &[u8], original_dynamic: &[u8]) -> Option<QuetzalSave> {
         let mut save = QuetzalSave::empty();
 
+        if !QuetzalHistory::chunk_fits(save_data) {
+            return None;
+        }
+
         let (form_header, _, form_body) = QuetzalSave::read_chunk(save_data);
-        if form_header != "FORM" {
-            panic!("Can't find FORM header, bad save file?")
+        if form_header != "FORM" || form_body.len() < 4 {
+            return None;
         }
 
-        let chunks = &form_body[4..]; // skip the IFZS string at the start
+        let chunks = &form_body[4..];
         let mut offset = 0;
 
-        while offset < chunks.len() {
+        while offset < chunks.len() && !save.is_complete() {
             let next = &chunks[offset..];
-            let (header, length, body) = QuetzalSave::read_chunk(next);
-
-            if header == "IFhd" {
-                save.read_ifhd_body(body);
-            } else if header == "Stks" {
-                save.read_stks_body(body);
-            } else if header == "CMem" {
-                save.read_cmem_body(body, original_dynamic);
-            } else if header == "UMem" {
-                save.memory = body.to_vec();
+            if !QuetzalHistory::chunk_fits(next) {
+                return None;
             }
 
-            // skip any other unnecessary chunks
-            if save.is_complete() {
-                break;
+            let (header, length, body) = QuetzalSave::read_chunk(next);
+
+            match header.as_str() {
+                "IFhd" if body.len() >= 13 => save.read_ifhd_body(body),
+                "Stks" if QuetzalSave::frames_fit(body) => save.read_stks_body(body),
+                "CMem" if QuetzalSave::cmem_fits(body, original_dynamic) => {
+                    save.read_cmem_body(body, original_dynamic)
+                }
+                "UMem" if body.len() == original_dynamic.len() => save.memory = body.to_vec(),
+                "IFhd" | "Stks" | "CMem" | "UMem" => return None,
+                _ => (),
             }
 
             offset += length;
         }
 
-        if !save.is_complete() {
-            panic!("Save file doesn't contain all necesary fields");
+        if save.is_complete() && save.memory.len() == original_dynamic.len() {
+            Some(save)
+        } else {
+            None
         }
-
-        save
     }
 
     pub fn make(
@@ -170,6 +176,11 @@ impl QuetzalSave {
     }
 
     fn read_cmem_body(&mut self, compressed: &[u8], original: &[u8]) {
+        self.memory = QuetzalSave::uncompress_memory(compressed, original);
+    }
+
+    // reverses `make_cmem_body`, rebuilding memory from the changes made to `original`
+    pub fn uncompress_memory(compressed: &[u8], original: &[u8]) -> Vec<u8> {
         let mut uncompressed = Vec::new();
         let mut index = 0;
 
@@ -197,14 +208,14 @@ impl QuetzalSave {
         }
 
         // XOR uncompressed with original to restore
-        self.memory = uncompressed
+        uncompressed
             .iter()
             .zip(original.iter())
             .map(|(a, b)| a ^ b)
-            .collect();
+            .collect()
     }
 
-    fn make_cmem_body(current: &[u8], original: &[u8]) -> Vec<u8> {
+    pub fn make_cmem_body(current: &[u8], original: &[u8]) -> Vec<u8> {
         // match each byte of the current and the original
         current.iter().zip(original.iter())
             // XOR current dynamic memory with the original (get what changed)
@@ -236,7 +247,49 @@ impl QuetzalSave {
             }).0 // <- compressed is the first field in the tuple
     }
 
+    // whether read_frames can walk the whole Stks body
+    fn frames_fit(bytes: &[u8]) -> bool {
+        let mut offset = 0;
+
+        while offset + 1 < bytes.len() {
+            if bytes.len() < offset + 8 {
+                return false;
+            }
+
+            let num_locals = bytes[offset + 3] & 0b0000_1111;
+            let stack_length = (usize::from(bytes[offset + 6]) << 8) + usize::from(bytes[offset + 7]);
+
+            offset += 8 + num_locals as usize * 2 + stack_length * 2;
+        }
+
+        !bytes.is_empty() && offset <= bytes.len()
+    }
+
+    // whether the CMem body is complete and no bigger than the original memory
+    fn cmem_fits(compressed: &[u8], original: &[u8]) -> bool {
+        let mut length = 0;
+        let mut index = 0;
+
+        while index < compressed.len() {
+            if compressed[index] != 0 {
+                length += 1;
+                index += 1;
+            } else if index + 1 < compressed.len() {
+                length += compressed[index + 1] as usize + 1;
+                index += 2;
+            } else {
+                return false;
+            }
+        }
+
+        length <= original.len()
+    }
+
     fn read_stks_body(&mut self, bytes: &[u8]) {
+        self.frames = QuetzalSave::read_frames(bytes);
+    }
+
+    pub fn read_frames(bytes: &[u8]) -> Vec<Frame> {
         let mut frames = Vec::new();
         let mut offset = 0;
 
@@ -258,10 +311,10 @@ impl QuetzalSave {
             offset += slice.len();
         }
 
-        self.frames = frames;
+        frames
     }
 
-    fn make_stks_body(frames: &[Frame]) -> Vec<u8> {
+    pub fn make_stks_body(frames: &[Frame]) -> Vec<u8> {
         let mut bytes = Vec::new();
 
         for frame in frames.iter() {
@@ -272,6 +325,203 @@ impl QuetzalSave {
     }
 }
 
+// A single node of the undo history tree, as it is stored on disk
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub parent: Option<usize>,
+    pub command: String,
+    pub location: String,
+    pub label: Option<String>,
+    pub state: Vec<u8>,
+}
+
+// Undo history that gets written next to a save file. It uses the same IFF
+// layout as a Quetzal save, and each state inside it is a complete save
+// (already CMem compressed against the original dynamic memory).
+#[derive(Debug)]
+pub struct QuetzalHistory {
+    pub entries: Vec<HistoryEntry>,
+    pub current: Option<usize>,
+}
+
+impl QuetzalHistory {
+    pub fn from_bytes(data: &[u8], chksum: u16) -> Option<QuetzalHistory> {
+        let mut history = QuetzalHistory {
+            entries: Vec::new(),
+            current: None,
+        };
+
+        // history files are optional, so bail out on anything unexpected
+        // instead of panicking like a bad save file would
+        if !QuetzalHistory::chunk_fits(data) {
+            return None;
+        }
+
+        let (form_header, _, form_body) = QuetzalSave::read_chunk(data);
+        if form_header != "FORM" || form_body.len() < 4 || &form_body[0..4] != b"IFZH" {
+            return None;
+        }
+
+        let chunks = &form_body[4..];
+        let mut offset = 0;
+        let mut found_header = false;
+
+        while offset < chunks.len() {
+            let next = &chunks[offset..];
+            if !QuetzalHistory::chunk_fits(next) {
+                return None;
+            }
+
+            let (header, length, body) = QuetzalSave::read_chunk(next);
+
+            if header == "IFhd" {
+                // only the checksum matters, to make sure this is the right game
+                if body.len() < 10 || (u16::from(body[8]) << 8) + u16::from(body[9]) != chksum {
+                    return None;
+                }
+                found_header = true;
+            } else if header == "Node" {
+                history.entries.push(QuetzalHistory::read_entry(body)?);
+            } else if header == "Curr" {
+                history.current = Some(QuetzalHistory::read_index(body)?);
+            }
+
+            offset += length;
+        }
+
+        // parents always come before their children, anything else is corrupt
+        let valid = history.entries.iter().enumerate().all(|(i, entry)| match entry.parent {
+            Some(parent) => parent < i,
+            None => true,
+        });
+
+        let valid_current = match history.current {
+            Some(index) => index < history.entries.len(),
+            None => true,
+        };
+
+        if found_header && valid && valid_current {
+            Some(history)
+        } else {
+            None
+        }
+    }
+
+    pub fn make(&self, chksum: u16, release: u16, serial: &[u8]) -> Vec<u8> {
+        let mut history_data = Vec::new();
+        let mut form_body = Vec::from(&b"IFZH"[..]);
+
+        let ifhd_body = QuetzalSave::make_ifhd_body(release, serial, chksum, 0);
+        QuetzalSave::write_chunk(&mut form_body, "IFhd", &ifhd_body[..]);
+
+        for entry in &self.entries {
+            QuetzalSave::write_chunk(&mut form_body, "Node", &QuetzalHistory::make_entry(entry));
+        }
+
+        if let Some(index) = self.current {
+            QuetzalSave::write_chunk(&mut form_body, "Curr", &QuetzalHistory::make_index(index));
+        }
+
+        QuetzalSave::write_chunk(&mut history_data, "FORM", &form_body[..]);
+
+        history_data
+    }
+
+    fn chunk_fits(data: &[u8]) -> bool {
+        if data.len() < 8 {
+            return false;
+        }
+
+        let body_length = ((data[4] as usize) << 24)
+            + ((data[5] as usize) << 16)
+            + ((data[6] as usize) << 8)
+            + data[7] as usize;
+
+        data.len() >= 8 + body_length
+    }
+
+    // indexes are 4 bytes, with 0xFFFFFFFF meaning "none"
+    fn read_index(bytes: &[u8]) -> Option<usize> {
+        if bytes.len() < 4 {
+            return None;
+        }
+
+        Some(
+            ((bytes[0] as usize) << 24)
+                + ((bytes[1] as usize) << 16)
+                + ((bytes[2] as usize) << 8)
+                + bytes[3] as usize,
+        )
+    }
+
+    fn make_index(index: usize) -> [u8; 4] {
+        [
+            ((index & 0xFF00_0000) >> 24) as u8,
+            ((index & 0x00FF_0000) >> 16) as u8,
+            ((index & 0x0000_FF00) >> 8) as u8,
+            (index & 0x0000_00FF) as u8,
+        ]
+    }
+
+    // strings are a 2 byte length followed by the utf8 bytes
+    fn read_string(bytes: &[u8], offset: &mut usize) -> Option<String> {
+        if bytes.len() < *offset + 2 {
+            return None;
+        }
+
+        let length = ((bytes[*offset] as usize) << 8) + bytes[*offset + 1] as usize;
+        let start = *offset + 2;
+
+        if bytes.len() < start + length {
+            return None;
+        }
+
+        *offset = start + length;
+        Some(String::from_utf8_lossy(&bytes[start..start + length]).into_owned())
+    }
+
+    fn write_string(bytes: &mut Vec<u8>, string: &str) {
+        let string = string.as_bytes();
+
+        bytes.push(((string.len() & 0xFF00) >> 8) as u8);
+        bytes.push((string.len() & 0x00FF) as u8);
+        bytes.extend(string);
+    }
+
+    // node body: parent index, command, location, label, then the save state
+    fn read_entry(body: &[u8]) -> Option<HistoryEntry> {
+        let parent = match QuetzalHistory::read_index(body)? {
+            0xFFFF_FFFF => None,
+            index => Some(index),
+        };
+
+        let mut offset = 4;
+        let command = QuetzalHistory::read_string(body, &mut offset)?;
+        let location = QuetzalHistory::read_string(body, &mut offset)?;
+        let label = QuetzalHistory::read_string(body, &mut offset)?;
+
+        Some(HistoryEntry {
+            parent,
+            command,
+            location,
+            label: if label.is_empty() { None } else { Some(label) },
+            state: body[offset..].to_vec(),
+        })
+    }
+
+    fn make_entry(entry: &HistoryEntry) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(entry.state.len() + 32);
+
+        bytes.extend(&QuetzalHistory::make_index(entry.parent.unwrap_or(0xFFFF_FFFF)));
+        QuetzalHistory::write_string(&mut bytes, &entry.command);
+        QuetzalHistory::write_string(&mut bytes, &entry.location);
+        QuetzalHistory::write_string(&mut bytes, entry.label.as_ref().map_or("", |l| &l[..]));
+        bytes.extend(&entry.state);
+
+        bytes
+    }
+}
+
 impl fmt::Display for QuetzalSave {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(
@@ -289,3 +539,90 @@ impl fmt::Display for QuetzalSave {
         write!(f, "")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{HistoryEntry, QuetzalHistory, QuetzalSave};
+    use frame::Frame;
+
+    fn entry(parent: Option<usize>, command: &str, label: Option<&str>) -> HistoryEntry {
+        let original = vec![0; 64];
+        let mut memory = original.clone();
+        memory[10] = command.len() as u8;
+
+        HistoryEntry {
+            parent,
+            command: command.to_string(),
+            location: String::from("West of House"),
+            label: label.map(String::from),
+            state: QuetzalSave::make(0x4F05, &memory, &original, &[Frame::empty()], 0xBEEF, 88, b"840726"),
+        }
+    }
+
+    #[test]
+    fn history_round_trips() {
+        let history = QuetzalHistory {
+            entries: vec![
+                entry(None, "", None),
+                entry(Some(0), "open mailbox", Some("start")),
+                entry(Some(0), "north", None),
+            ],
+            current: Some(2),
+        };
+
+        let data = history.make(0xBEEF, 88, b"840726");
+        let read = QuetzalHistory::from_bytes(&data, 0xBEEF).unwrap();
+
+        assert_eq!(read.current, Some(2));
+        assert_eq!(read.entries.len(), 3);
+
+        for (read, written) in read.entries.iter().zip(&history.entries) {
+            assert_eq!(read.parent, written.parent);
+            assert_eq!(read.command, written.command);
+            assert_eq!(read.location, written.location);
+            assert_eq!(read.label, written.label);
+            assert_eq!(read.state, written.state);
+        }
+
+        assert_eq!(read.make(0xBEEF, 88, b"840726"), data);
+    }
+
+    #[test]
+    fn history_for_another_game_is_rejected() {
+        let history = QuetzalHistory {
+            entries: vec![entry(None, "", None)],
+            current: Some(0),
+        };
+
+        let data = history.make(0xBEEF, 88, b"840726");
+
+        assert!(QuetzalHistory::from_bytes(&data, 0xBEEE).is_none());
+    }
+
+    #[test]
+    fn truncated_history_is_rejected() {
+        let history = QuetzalHistory {
+            entries: vec![entry(None, "", None), entry(Some(0), "look", None)],
+            current: Some(1),
+        };
+
+        let data = history.make(0xBEEF, 88, b"840726");
+
+        assert!(QuetzalHistory::from_bytes(&data[..data.len() - 3], 0xBEEF).is_none());
+    }
+
+    #[test]
+    fn bad_states_are_rejected_without_panicking() {
+        let original = vec![0; 64];
+        let state = entry(None, "look", None).state;
+
+        let save = QuetzalSave::try_from_bytes(&state, &original).unwrap();
+        assert_eq!(save.pc, 0x4F05);
+        assert_eq!(save.memory[10], 4);
+
+        // too short, cut off inside a chunk, or bigger than the game's memory
+        assert!(QuetzalSave::try_from_bytes(&state[..6], &original).is_none());
+        assert!(QuetzalSave::try_from_bytes(&state[..state.len() - 2], &original).is_none());
+        assert!(QuetzalSave::try_from_bytes(&state, &original[..8]).is_none());
+    }
+}
@@ -0,0 +1,214 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// A set of SGR codes (ESC [ codes m), ex: "bold yellow on blue" => [1, 33, 44]
+#[derive(Debug, Clone, Default)]
+pub struct Style {
+    codes: Vec<u8>,
+}
+
+impl Style {
+    pub fn parse(text: &str) -> Result<Style, String> {
+        let mut codes = Vec::new();
+        let mut words = text.split_whitespace();
+
+        while let Some(word) = words.next() {
+            let code = match word {
+                "plain" | "none" => continue,
+                "bold" => 1,
+                "dim" => 2,
+                "italic" => 3,
+                "underline" => 4,
+                "reverse" => 7,
+                "on" => {
+                    let color = words.next().ok_or("missing color after \"on\"")?;
+                    color_code(color).map(|code| code + 10)?
+                }
+                color => color_code(color)?,
+            };
+
+            codes.push(code);
+        }
+
+        Ok(Style { codes })
+    }
+
+    // just the attributes (bold, underline...) without any colors
+    fn without_colors(&self) -> Style {
+        let codes = self.codes
+            .iter()
+            .cloned()
+            .filter(|code| !matches!(*code, 30..=49 | 90..=107))
+            .collect();
+
+        Style { codes }
+    }
+
+    pub fn escape(&self) -> String {
+        if self.codes.is_empty() {
+            return String::new();
+        }
+
+        let codes: Vec<String> = self.codes.iter().map(|code| code.to_string()).collect();
+        format!("\x1B[{}m", codes.join(";"))
+    }
+}
+
+fn color_code(name: &str) -> Result<u8, String> {
+    let colors = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+    if name == "default" {
+        return Ok(39);
+    }
+
+    let (bright, base) = match name.strip_prefix("bright-") {
+        Some(base) => (true, base),
+        None => (false, name),
+    };
+
+    match colors.iter().position(|color| *color == base) {
+        Some(n) if bright => Ok(90 + n as u8),
+        Some(n) => Ok(30 + n as u8),
+        None => Err(format!("unknown color or style \"{}\"", name)),
+    }
+}
+
+// How the terminal ui looks. Themes are files with one setting per line:
+//
+//     # comments start with a hash
+//     foreground = white
+//     background = black
+//     room = bold bright-white
+//     object = bold
+//     debug = dim cyan
+//     input = italic
+//
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub text: Style,
+    pub room: Style,
+    pub object: Style,
+    pub debug: Style,
+    pub input: Style,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            text: Style::default(),
+            room: Style { codes: vec![37, 1] },
+            object: Style { codes: vec![37, 1] },
+            debug: Style { codes: vec![36] },
+            input: Style::default(),
+        }
+    }
+}
+
+impl Theme {
+    // no styling at all (the status line is still in reverse video)
+    pub fn plain() -> Theme {
+        Theme {
+            text: Style::default(),
+            room: Style::default(),
+            object: Style::default(),
+            debug: Style::default(),
+            input: Style::default(),
+        }
+    }
+
+    pub fn named(name: &str) -> Option<Theme> {
+        let parse = |text: &str| Style::parse(text).unwrap();
+
+        match name {
+            "default" => Some(Theme::default()),
+            "none" | "plain" => Some(Theme::plain()),
+            "light" => Some(Theme {
+                text: parse("black on bright-white"),
+                room: parse("bold blue"),
+                object: parse("bold"),
+                debug: parse("magenta"),
+                input: parse("green"),
+            }),
+            "dark" => Some(Theme {
+                text: parse("bright-white on black"),
+                room: parse("bold bright-yellow"),
+                object: parse("bold bright-cyan"),
+                debug: parse("dim green"),
+                input: parse("bright-green"),
+            }),
+            _ => None,
+        }
+    }
+
+    // starts with the default theme and changes whatever the file sets
+    pub fn from_file(path: &Path) -> Result<Theme, String> {
+        let mut text = String::new();
+
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|err| format!("Couldn't read theme {}: {}", path.to_string_lossy(), err))?;
+
+        let mut theme = Theme::default();
+        let mut foreground = Style::default();
+        let mut background = Style::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |msg: String| format!("Theme error on line {}: {}", number + 1, msg);
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = parts
+                .next()
+                .ok_or_else(|| error(String::from("expected \"key = value\"")))?;
+
+            let style = if key == "background" {
+                Style::parse(&format!("on {}", value))
+            } else {
+                Style::parse(value)
+            };
+            let style = style.map_err(&error)?;
+
+            match key {
+                "foreground" => foreground = style,
+                "background" => background = style,
+                "room" => theme.room = style,
+                "object" => theme.object = style,
+                "debug" => theme.debug = style,
+                "input" => theme.input = style,
+                _ => return Err(error(format!("unknown setting \"{}\"", key))),
+            }
+        }
+
+        theme.text.codes = foreground.codes;
+        theme.text.codes.extend(background.codes);
+
+        Ok(theme)
+    }
+
+    // where a theme is picked up from if there isn't one on the command line
+    pub fn config_path() -> Option<PathBuf> {
+        let config = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+        Some(config.join("encrusted").join("theme"))
+    }
+
+    // respects NO_COLOR (https://no-color.org), but keeps bold/underline/etc
+    pub fn without_colors(&self) -> Theme {
+        Theme {
+            text: self.text.without_colors(),
+            room: self.room.without_colors(),
+            object: self.object.without_colors(),
+            debug: self.debug.without_colors(),
+            input: self.input.without_colors(),
+        }
+    }
+}
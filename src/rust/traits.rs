@@ -5,12 +5,28 @@ pub trait UI {
     fn clear(&self);
     fn print(&mut self, text: &str);
     fn debug(&mut self, text: &str);
-    fn print_object(&mut self, object: &str);
-    fn set_status_bar(&self, left: &str, right: &str);
+    fn print_object(&mut self, number: u16, name: &str);
+    fn set_status_bar(&mut self, left: &str, right: &str);
+
+    // screen model: a lower window for the main text and an upper window
+    // (status lines, quotes, menus) that is split off from the top
+    fn split_window(&mut self, lines: u16);
+    fn set_window(&mut self, window: u16);
+    fn erase_window(&mut self, window: i16);
+    fn erase_line(&mut self);
+    fn set_cursor(&mut self, line: u16, column: u16);
+    fn get_cursor(&self) -> (u16, u16);
+    fn set_text_style(&mut self, style: u16);
+    // (width, height) in characters
+    fn get_screen_size(&mut self) -> (usize, usize);
 
     // only used by terminal ui
-    fn reset(&self);
-    fn get_user_input(&self) -> String;
+    fn reset(&mut self);
+    fn get_user_input(&mut self) -> String;
+    // a single keypress as a zscii code (for read_char)
+    fn get_user_char(&mut self) -> u16;
+    // dictionary words, which only keep the first `resolution` letters
+    fn set_completions(&mut self, words: Vec<String>, resolution: usize);
 
     // only used by web ui
     fn flush(&mut self);
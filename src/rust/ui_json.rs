@@ -0,0 +1,166 @@
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::io;
+use std::io::Write;
+
+use serde_json;
+use serde_json::{Map, Value};
+
+use encrusted::UI;
+
+// Prints one JSON object per turn, for scripts and bots (--json):
+//
+//     {"text": ["West of House", "You are standing..."],
+//      "room": {"number": 180, "name": "West of House"},
+//      "status": "0/1", "score": 0, "turns": 1,
+//      "inventory": [], "objects": ["small mailbox"], "input": "line"}
+//
+// The text is split into paragraphs (one per line of game output), and the
+// rest comes in as messages from the driver in main before each flush.
+// "status", "score" and "turns" come from the v1-3 status line, and are left
+// out for v4+ games: they print their own status in the upper window, and
+// don't keep the score or turns anywhere standard. The
+// turn that ends the game also has a base64 "savestate" from just before the
+// quit command, to carry on from with {"meta": "restore", "data": ...}.
+#[derive(Debug)]
+pub struct JsonUI {
+    text: String,
+    window: u16,
+    status: Option<(String, String)>,
+    fields: RefCell<Map<String, Value>>,
+}
+
+impl JsonUI {
+    fn paragraphs(&self) -> Vec<String> {
+        let mut paragraphs: Vec<String> = self.text
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect();
+
+        // the prompt is redundant with the input marker
+        if paragraphs.last().map(String::as_str) == Some(">") {
+            paragraphs.pop();
+        }
+
+        paragraphs
+    }
+
+    fn blocking_input_error(&mut self) {
+        let msg = json!("Input can only be sent as a JSON command").to_string();
+        self.message("error", &msg);
+        self.flush();
+    }
+}
+
+impl UI for JsonUI {
+    fn new() -> Box<JsonUI> {
+        Box::new(JsonUI {
+            text: String::new(),
+            window: 0,
+            status: None,
+            fields: RefCell::new(Map::new()),
+        })
+    }
+
+    fn print(&mut self, text: &str) {
+        // upper window text is mostly status lines & menus, skip it
+        if self.window != 1 {
+            self.text.push_str(text);
+        }
+    }
+
+    fn debug(&mut self, text: &str) {
+        self.text.push_str(text);
+    }
+
+    fn print_object(&mut self, _number: u16, name: &str) {
+        self.print(name);
+    }
+
+    fn set_status_bar(&mut self, left: &str, right: &str) {
+        self.status = Some((String::from(left), String::from(right)));
+    }
+
+    fn set_window(&mut self, window: u16) {
+        self.window = window;
+    }
+
+    fn erase_window(&mut self, window: i16) {
+        if window == -1 {
+            self.window = 0;
+        }
+    }
+
+    // "save"/"savestate" messages are (status, base64 data), the others are
+    // already json (or plain strings)
+    fn message(&self, mtype: &str, msg: &str) {
+        if msg.is_empty() {
+            return;
+        }
+
+        let value = match mtype {
+            "save" | "savestate" => {
+                let (_, data): (String, String) = serde_json::from_str(msg).unwrap();
+                Value::String(data)
+            }
+            _ => serde_json::from_str(msg).unwrap_or_else(|_| Value::String(String::from(msg))),
+        };
+
+        self.fields.borrow_mut().insert(String::from(mtype), value);
+    }
+
+    fn flush(&mut self) {
+        let mut turn = Map::new();
+        turn.insert(String::from("text"), json!(self.paragraphs()));
+
+        // (never set for v4+, see above)
+        if let Some((_, ref right)) = self.status {
+            turn.insert(String::from("status"), json!(right));
+
+            let mut parts = right.split('/').map(|part| part.parse::<i16>());
+            if let (Some(Ok(score)), Some(Ok(turns))) = (parts.next(), parts.next()) {
+                turn.insert(String::from("score"), json!(score));
+                turn.insert(String::from("turns"), json!(turns));
+            }
+        }
+
+        turn.extend(self.fields.borrow_mut().clone());
+        self.fields.borrow_mut().clear();
+
+        println!("{}", Value::Object(turn));
+        io::stdout().flush().unwrap();
+
+        self.text.clear();
+    }
+
+    fn get_screen_size(&mut self) -> (usize, usize) {
+        (80, 255)
+    }
+
+    fn get_cursor(&self) -> (u16, u16) {
+        (1, 1)
+    }
+
+    // input comes from the driver in main, so if anything still asks for it
+    // the old blocking way it gets an error event and nothing typed
+    fn get_user_input(&mut self) -> String {
+        self.blocking_input_error();
+        String::new()
+    }
+
+    fn get_user_char(&mut self) -> u16 {
+        self.blocking_input_error();
+        13
+    }
+
+    // unused in json mode
+    fn clear(&self) {}
+    fn reset(&mut self) {}
+    fn set_completions(&mut self, _words: Vec<String>, _resolution: usize) {}
+    fn split_window(&mut self, _lines: u16) {}
+    fn erase_line(&mut self) {}
+    fn set_cursor(&mut self, _line: u16, _column: u16) {}
+    fn set_text_style(&mut self, _style: u16) {}
+}
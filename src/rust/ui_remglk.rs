@@ -0,0 +1,467 @@
+use std::boxed::Box;
+use std::io;
+use std::io::{BufRead, Write};
+use std::process;
+
+use serde_json;
+use serde_json::Value;
+
+use encrusted::UI;
+
+const BUFFER_WINDOW: u32 = 1;
+const GRID_WINDOW: u32 = 2;
+
+// Screen size used until the front end sends its metrics
+const DEFAULT_METRICS: (usize, usize, usize, usize) = (800, 480, 10, 12);
+
+// Talks the RemGlk/GlkOte JSON protocol on stdin/stdout, so encrusted can be
+// driven by GlkOte-style front ends (or `regtest.py -r`). Input events come in
+// as JSON objects, and every time input is needed a single "update" object is
+// sent back with new window contents and an input request.
+//
+// There's a buffer window for the main text, and a grid window at the top for
+// the v3 status line and the v4+ upper window.
+//
+// See http://eblong.com/zarf/glk/glkote/docs.html
+#[derive(Debug)]
+pub struct RemGlkUI {
+    gen: u32,
+    // screen size in characters (from the metrics)
+    width: usize,
+    height: usize,
+    char_size: (usize, usize),
+    // buffer window lines for the next update
+    lines: Vec<Value>,
+    at_line_start: bool,
+    clear_buffer: bool,
+    style: &'static str,
+    // grid window: status line (v1-3) on top, then the upper window
+    status_line: bool,
+    upper_lines: usize,
+    grid: Vec<Vec<char>>,
+    grid_dirty: bool,
+    layout_dirty: bool,
+    window: u16,
+    cursor: (usize, usize),
+}
+
+impl RemGlkUI {
+    // reads events until one of the given type comes in (layout changes are
+    // handled along the way)
+    fn wait_for(&mut self, wanted: &str) -> Value {
+        let stdin = io::stdin();
+
+        loop {
+            let mut line = String::new();
+
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                // front end went away
+                process::exit(0);
+            }
+
+            let event: Value = match serde_json::from_str(line.trim()) {
+                Ok(event) => event,
+                Err(_) => continue,
+            };
+
+            match event["type"].as_str() {
+                Some(kind) if kind == wanted => return event,
+                Some("init") | Some("arrange") => {
+                    self.set_metrics(&event["metrics"]);
+                    self.send_update(None);
+                }
+                Some("refresh") => {
+                    self.layout_dirty = true;
+                    self.grid_dirty = true;
+                    self.send_update(None);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn set_metrics(&mut self, metrics: &Value) {
+        let get = |key: &str, default: usize| {
+            metrics[key].as_f64().map_or(default, |value| value as usize).max(1)
+        };
+
+        let (width, height, char_width, char_height) = DEFAULT_METRICS;
+        let char_width = get("buffercharwidth", get("charwidth", char_width));
+        let char_height = get("buffercharheight", get("charheight", char_height));
+
+        self.char_size = (char_width, char_height);
+        self.width = get("width", width) / char_width;
+        self.height = get("height", height) / char_height;
+        self.layout_dirty = true;
+        self.resize_grid();
+    }
+
+    fn grid_height(&self) -> usize {
+        self.upper_lines + if self.status_line { 1 } else { 0 }
+    }
+
+    fn resize_grid(&mut self) {
+        let (width, height) = (self.width, self.grid_height());
+
+        self.grid.resize(height, Vec::new());
+        for row in &mut self.grid {
+            row.resize(width, ' ');
+        }
+
+        self.grid_dirty = true;
+    }
+
+    // (window positions are in pixels)
+    fn windows(&self) -> Value {
+        let (char_width, char_height) = self.char_size;
+        let mut windows = vec![json!({
+            "id": BUFFER_WINDOW,
+            "type": "buffer",
+            "rock": 201,
+            "left": 0,
+            "top": self.grid_height() * char_height,
+            "width": self.width * char_width,
+            "height": self.height.saturating_sub(self.grid_height()) * char_height,
+        })];
+
+        if self.grid_height() > 0 {
+            windows.push(json!({
+                "id": GRID_WINDOW,
+                "type": "grid",
+                "rock": 202,
+                "left": 0,
+                "top": 0,
+                "width": self.width * char_width,
+                "height": self.grid_height() * char_height,
+                "gridwidth": self.width,
+                "gridheight": self.grid_height(),
+            }));
+        }
+
+        Value::Array(windows)
+    }
+
+    fn add_text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+
+        let span = json!({ "style": self.style, "text": text });
+
+        // continue the last line, even from an earlier update
+        if !self.at_line_start {
+            if let Some(line) = self.lines.last_mut() {
+                let content = line["content"].as_array_mut().unwrap();
+
+                // runs of the same style go in one span
+                match content.last_mut() {
+                    Some(last) if last["style"] == span["style"] => {
+                        let joined = format!("{}{}", last["text"].as_str().unwrap(), text);
+                        last["text"] = Value::String(joined);
+                    }
+                    _ => content.push(span),
+                }
+                return;
+            }
+
+            self.lines.push(json!({ "append": true, "content": [span] }));
+        } else {
+            self.lines.push(json!({ "content": [span] }));
+            self.at_line_start = false;
+        }
+    }
+
+    fn add_newline(&mut self) {
+        if self.at_line_start {
+            // a blank line
+            self.lines.push(json!({}));
+        }
+
+        self.at_line_start = true;
+    }
+
+    fn print_grid(&mut self, text: &str) {
+        let top = if self.status_line { 1 } else { 0 };
+
+        for c in text.chars() {
+            let (row, column) = self.cursor;
+
+            if c == '\n' {
+                self.cursor = (row + 1, 0);
+            } else if row < self.upper_lines && column < self.width {
+                self.grid[top + row][column] = c;
+                self.cursor = (row, column + 1);
+            }
+        }
+
+        self.grid_dirty = true;
+    }
+
+    fn send_update(&mut self, input: Option<Value>) {
+        self.gen += 1;
+
+        let mut update = json!({ "type": "update", "gen": self.gen });
+        let mut content = Vec::new();
+
+        if self.layout_dirty {
+            update["windows"] = self.windows();
+            self.layout_dirty = false;
+        }
+
+        if self.clear_buffer || !self.lines.is_empty() {
+            let mut buffer = json!({ "id": BUFFER_WINDOW, "text": self.lines.drain(..).collect::<Vec<_>>() });
+            if self.clear_buffer {
+                buffer["clear"] = Value::Bool(true);
+                self.clear_buffer = false;
+            }
+            content.push(buffer);
+        }
+
+        if self.grid_dirty && self.grid_height() > 0 {
+            let lines: Vec<Value> = self.grid
+                .iter()
+                .enumerate()
+                .map(|(i, row)| {
+                    let text: String = row.iter().collect();
+                    json!({ "line": i, "content": [{ "style": "normal", "text": text }] })
+                })
+                .collect();
+
+            content.push(json!({ "id": GRID_WINDOW, "lines": lines }));
+        }
+        self.grid_dirty = false;
+
+        if !content.is_empty() {
+            update["content"] = Value::Array(content);
+        }
+
+        if let Some(input) = input {
+            update["input"] = json!([input]);
+        }
+
+        println!("{}", update);
+        io::stdout().flush().unwrap();
+    }
+}
+
+impl UI for RemGlkUI {
+    fn new() -> Box<RemGlkUI> {
+        let (width, height, char_width, char_height) = DEFAULT_METRICS;
+
+        let mut ui = RemGlkUI {
+            gen: 0,
+            width: width / char_width,
+            height: height / char_height,
+            char_size: (char_width, char_height),
+            lines: Vec::new(),
+            at_line_start: true,
+            clear_buffer: false,
+            style: "normal",
+            status_line: false,
+            upper_lines: 0,
+            grid: Vec::new(),
+            grid_dirty: false,
+            layout_dirty: true,
+            window: 0,
+            cursor: (0, 0),
+        };
+
+        // the front end starts things off with its screen size
+        let init = ui.wait_for("init");
+        ui.set_metrics(&init["metrics"]);
+
+        Box::new(ui)
+    }
+
+    fn clear(&self) {}
+
+    fn print(&mut self, text: &str) {
+        if self.window == 1 {
+            self.print_grid(text);
+            return;
+        }
+
+        let lines = text.split('\n').collect::<Vec<_>>();
+        let num_lines = lines.len();
+
+        for (i, line) in lines.iter().enumerate() {
+            self.add_text(line);
+
+            if i < num_lines - 1 {
+                self.add_newline();
+            }
+        }
+    }
+
+    fn debug(&mut self, text: &str) {
+        let style = self.style;
+        self.style = "preformatted";
+        self.print(text);
+        self.style = style;
+    }
+
+    fn print_object(&mut self, _number: u16, name: &str) {
+        self.print(name);
+    }
+
+    fn set_status_bar(&mut self, left: &str, right: &str) {
+        if !self.status_line {
+            self.status_line = true;
+            self.upper_lines = self.upper_lines.min(self.height.saturating_sub(2));
+            self.layout_dirty = true;
+            self.grid.insert(0, Vec::new());
+            self.resize_grid();
+        }
+
+        let left = format!(" {}", left);
+        let right = format!("{} ", right);
+        let used = left.chars().count() + right.chars().count();
+        let padding = " ".repeat(self.width.saturating_sub(used).max(1));
+
+        let mut row: Vec<char> = format!("{}{}{}", left, padding, right).chars().collect();
+        row.resize(self.width, ' ');
+
+        self.grid[0] = row;
+        self.grid_dirty = true;
+    }
+
+    fn split_window(&mut self, lines: u16) {
+        let top = if self.status_line { 1 } else { 0 };
+        let lines = (lines as usize).min(self.height.saturating_sub(top + 1));
+
+        if lines != self.upper_lines {
+            self.upper_lines = lines;
+            self.layout_dirty = true;
+            self.resize_grid();
+        }
+
+        if self.cursor.0 >= lines {
+            self.cursor = (0, 0);
+        }
+    }
+
+    fn set_window(&mut self, window: u16) {
+        self.window = window;
+
+        if window == 1 {
+            self.cursor = (0, 0);
+        }
+    }
+
+    fn erase_window(&mut self, window: i16) {
+        let top = if self.status_line { 1 } else { 0 };
+
+        if window == -1 {
+            self.split_window(0);
+            self.window = 0;
+        }
+
+        if window <= 0 {
+            self.lines.clear();
+            self.at_line_start = true;
+            self.clear_buffer = true;
+        }
+
+        if window < 0 || window == 1 {
+            for row in self.grid.iter_mut().skip(top) {
+                for c in row.iter_mut() {
+                    *c = ' ';
+                }
+            }
+
+            self.cursor = (0, 0);
+            self.grid_dirty = true;
+        }
+    }
+
+    fn erase_line(&mut self) {
+        let top = if self.status_line { 1 } else { 0 };
+        let (row, column) = self.cursor;
+
+        if self.window == 1 && row < self.upper_lines {
+            for c in self.grid[top + row].iter_mut().skip(column) {
+                *c = ' ';
+            }
+            self.grid_dirty = true;
+        }
+    }
+
+    fn set_cursor(&mut self, line: u16, column: u16) {
+        if self.window == 1 {
+            let row = (line as usize).saturating_sub(1);
+            let column = (column as usize).saturating_sub(1);
+            self.cursor = (row, column);
+        }
+    }
+
+    fn get_cursor(&self) -> (u16, u16) {
+        (self.cursor.0 as u16 + 1, self.cursor.1 as u16 + 1)
+    }
+
+    fn set_text_style(&mut self, style: u16) {
+        self.style = match style {
+            0 => "normal",
+            s if s & 8 != 0 => "preformatted",
+            s if s & 2 != 0 => "subheader",
+            s if s & 4 != 0 => "emphasized",
+            _ => "alert",
+        };
+    }
+
+    fn get_screen_size(&mut self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    // whatever was printed after the last input request
+    fn reset(&mut self) {
+        self.send_update(None);
+    }
+
+    fn get_user_input(&mut self) -> String {
+        let gen = self.gen + 1;
+        self.send_update(Some(json!({
+            "id": BUFFER_WINDOW,
+            "gen": gen,
+            "type": "line",
+            "maxlen": 255,
+        })));
+
+        let event = self.wait_for("line");
+        let input = String::from(event["value"].as_str().unwrap_or(""));
+
+        // the input gets echoed on the prompt line, like a terminal would
+        let style = self.style;
+        self.style = "input";
+        self.add_text(&input);
+        self.style = style;
+        self.add_newline();
+
+        input
+    }
+
+    fn get_user_char(&mut self) -> u16 {
+        let gen = self.gen + 1;
+        let window = if self.window == 1 { GRID_WINDOW } else { BUFFER_WINDOW };
+
+        self.send_update(Some(json!({ "id": window, "gen": gen, "type": "char" })));
+
+        let event = self.wait_for("char");
+        let value = event["value"].as_str().unwrap_or("");
+
+        match value {
+            "return" => 13,
+            "delete" => 8,
+            "escape" => 27,
+            "tab" => 9,
+            "up" => 129,
+            "down" => 130,
+            "left" => 131,
+            "right" => 132,
+            _ => value.chars().next().map_or(13, |c| if c.is_ascii() { c as u16 } else { '?' as u16 }),
+        }
+    }
+
+    fn set_completions(&mut self, _words: Vec<String>, _resolution: usize) {}
+    fn flush(&mut self) {}
+    fn message(&self, _mtype: &str, _msg: &str) {}
+}
@@ -3,12 +3,20 @@
 use std::boxed::Box;
 use std::io;
 use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use regex::Regex;
 use atty::Stream;
+use libc;
 use term_size;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-use traits::UI;
+use line_editor;
+use line_editor::LineEditor;
+use theme::{Style, Theme};
+use encrusted::UI;
 
 lazy_static! {
     static ref ANSI_RE: Regex = Regex::new(
@@ -16,19 +24,315 @@ lazy_static! {
     ).unwrap();
 }
 
+// width to use when the terminal size is unknown
+const DEFAULT_WIDTH: usize = 80;
+const TAB_WIDTH: usize = 8;
+
+// set by the SIGWINCH handler, the new size gets picked up before the next print
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_resize(_signal: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+// cuts a string down to fit in some number of columns
+fn fit_width(text: &str, width: usize) -> String {
+    let mut used = 0;
+
+    text.chars()
+        .take_while(|c| {
+            used += c.width().unwrap_or(0);
+            used <= width
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct TerminalUI {
     isatty: bool,
     width: usize,
     x_position: usize,
+    // full-screen mode: status line and upper window stay at the top of the
+    // screen, and the lower window scrolls underneath them
+    fullscreen: bool,
+    height: usize,
+    status_line: bool,
+    upper_lines: usize,
+    window: u16,
+    style: u16,
+    // cursor positions (1-based), lower window column is `x_position`
+    lower_row: usize,
+    upper_row: usize,
+    upper_col: usize,
+    editor: LineEditor,
+    // input is coming from someone at a keyboard (not piped in)
+    interactive: bool,
+    // lines printed since the last input, for the [MORE] prompt
+    paged_lines: usize,
+    // width was given on the command line, so it doesn't follow resizes
+    fixed_width: bool,
+    theme: Theme,
+    // an object name at the start of a line might be a room title, which
+    // isn't known until the next thing is printed
+    pending_object: Option<String>,
 }
 
 impl TerminalUI {
+    // Uses the whole terminal (falls back to the plain ui if not a tty)
+    pub fn fullscreen() -> Box<TerminalUI> {
+        let mut ui = TerminalUI::new();
+
+        if ui.is_term() && ui.height > 0 {
+            ui.fullscreen = true;
+        }
+
+        ui
+    }
+
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    // overrides the terminal's width
+    pub fn set_width(&mut self, width: usize) {
+        self.width = width;
+        self.fixed_width = true;
+    }
+
+    // picks up the new size if the terminal was resized
+    fn check_resize(&mut self) {
+        if !RESIZED.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Some((w, h)) = term_size::dimensions() {
+            if !self.fixed_width {
+                self.width = w;
+                self.x_position = self.x_position.min(w);
+            }
+
+            self.height = h;
+
+            if self.fullscreen {
+                // the upper window has to shrink if the screen got too small
+                self.upper_lines = self.upper_lines.min(h.saturating_sub(self.upper_top()));
+                self.lower_row = self.lower_row.min(h);
+                self.update_scroll_region();
+            }
+        }
+    }
+
+    // previous commands are kept in a file, so they can be recalled next time
+    pub fn load_history(&mut self, path: &Path) {
+        self.editor.load_history(path);
+    }
+
     fn print_raw(&self, raw: &str) {
         print!("{}", raw);
         io::stdout().flush().unwrap();
     }
 
+    fn move_cursor(&self, row: usize, column: usize) {
+        self.print_raw(&format!("\x1B[{};{}H", row, column));
+    }
+
+    // puts the cursor back wherever the current window left off
+    fn restore_cursor(&self) {
+        if self.window == 1 {
+            self.move_cursor(self.upper_top() + self.upper_row - 1, self.upper_col);
+        } else {
+            self.move_cursor(self.lower_row, self.x_position + 1);
+        }
+    }
+
+    // first row of the upper window (just below the status line)
+    fn upper_top(&self) -> usize {
+        if self.status_line {
+            2
+        } else {
+            1
+        }
+    }
+
+    // first row of the lower window
+    fn lower_top(&self) -> usize {
+        self.upper_top() + self.upper_lines
+    }
+
+    // Only the lower window scrolls: ESC [top;bottom r
+    // (this also moves the cursor, so it has to be put back)
+    fn update_scroll_region(&mut self) {
+        if self.lower_row < self.lower_top() {
+            self.lower_row = self.lower_top();
+            self.x_position = 0;
+        }
+
+        self.print_raw(&format!("\x1B[{};{}r", self.lower_top(), self.height));
+        self.restore_cursor();
+    }
+
+    fn clear_rows(&self, first: usize, last: usize) {
+        for row in first..=last {
+            // Erase line: ESC [2K
+            self.move_cursor(row, 1);
+            self.print_raw("\x1B[2K");
+        }
+    }
+
+    fn style_codes(&self) -> String {
+        // Reset: ESC [0m, then the theme's colors and reverse video/bold/italic
+        let mut codes = String::from("\x1B[0m");
+        codes.push_str(&self.theme.text.escape());
+
+        if self.style & 1 != 0 {
+            codes.push_str("\x1B[7m");
+        }
+        if self.style & 2 != 0 {
+            codes.push_str("\x1B[1m");
+        }
+        if self.style & 4 != 0 {
+            codes.push_str("\x1B[3m");
+        }
+
+        codes
+    }
+
+    fn newline(&mut self) {
+        println!();
+        self.x_position = 0;
+
+        // the bottom line just scrolls
+        if self.fullscreen && self.lower_row < self.height {
+            self.lower_row += 1;
+        }
+
+        self.paged_lines += 1;
+
+        if self.interactive && self.paged_lines >= self.page_size() {
+            self.show_more();
+        }
+    }
+
+    // how many lines fit before the first one (since the last input) would
+    // scroll off the screen
+    fn page_size(&self) -> usize {
+        let lines = if self.fullscreen {
+            self.height + 1 - self.lower_top()
+        } else {
+            self.height
+        };
+
+        // no point in paging a tiny (or unknown) screen
+        if lines < 3 {
+            usize::MAX
+        } else {
+            lines - 1
+        }
+    }
+
+    // Waits for a key before printing any more, then erases the prompt
+    fn show_more(&mut self) {
+        self.print_raw("\x1B[0m\x1B[7m[MORE]\x1B[0m");
+
+        if !line_editor::wait_for_key() {
+            self.reset();
+            process::exit(0);
+        }
+
+        let codes = self.style_codes();
+        self.print_raw(&format!("\r\x1B[K{}", codes));
+        self.paged_lines = 0;
+    }
+
+    // Prints a single line (no \n's), moving words that don't fit on to the next
+    // line. Widths are in columns, so wide characters take up 2 and tabs go
+    // to the next tab stop. Spaces at the end of a wrapped line are left out.
+    fn print_wrapped(&mut self, line: &str) {
+        let is_space = |c: char| c == ' ' || c == '\t';
+        let mut rest = line;
+
+        while !rest.is_empty() {
+            let starts_with_space = rest.starts_with(is_space);
+
+            let end = rest
+                .find(|c: char| is_space(c) != starts_with_space)
+                .unwrap_or(rest.len());
+            let (chunk, remaining) = rest.split_at(end);
+            rest = remaining;
+
+            if starts_with_space {
+                let mut spaces = 0;
+                for c in chunk.chars() {
+                    let x = self.x_position + spaces;
+                    spaces += if c == '\t' { TAB_WIDTH - x % TAB_WIDTH } else { 1 };
+                }
+
+                // the spaces only get printed if the next word fits after them
+                let next_word = rest.split(is_space).next().unwrap_or("");
+
+                if self.x_position + spaces + next_word.width() > self.width {
+                    self.newline();
+                } else {
+                    print!("{}", " ".repeat(spaces));
+                    self.x_position += spaces;
+                }
+            } else {
+                let width = chunk.width();
+
+                if self.x_position + width > self.width && self.x_position > 0 {
+                    self.newline();
+                }
+
+                print!("{}", chunk);
+
+                // words longer than a whole line get wrapped by the terminal
+                self.x_position += width;
+                if self.x_position > self.width {
+                    self.x_position %= self.width.max(1);
+                }
+            }
+        }
+    }
+
+    fn print_styled(&mut self, text: &str, style: &Style) {
+        self.print_raw(&style.escape());
+        self.print(text);
+
+        let codes = self.style_codes();
+        self.print_raw(&codes);
+    }
+
+    // a room title is an object name on a line of its own
+    fn flush_object(&mut self, is_room: bool) {
+        if let Some(object) = self.pending_object.take() {
+            let style = if is_room {
+                self.theme.room.clone()
+            } else {
+                self.theme.object.clone()
+            };
+
+            self.print_styled(&object, &style);
+        }
+    }
+
+    // upper window text doesn't wrap or scroll, it's placed with the cursor
+    fn print_upper(&mut self, text: &str) {
+        self.restore_cursor();
+
+        for c in text.chars() {
+            if c == '\n' {
+                self.upper_row += 1;
+                self.upper_col = 1;
+                self.restore_cursor();
+            } else if self.upper_row <= self.upper_lines && self.upper_col <= self.width {
+                print!("{}", c);
+                self.upper_col += c.width().unwrap_or(0);
+            }
+        }
+
+        io::stdout().flush().unwrap();
+    }
+
     fn enter_alternate_screen(&self) {
         if self.is_term() {
             self.print_raw("\x1B[?1049h");
@@ -48,22 +352,49 @@ impl TerminalUI {
 
 impl UI for TerminalUI {
     fn new() -> Box<TerminalUI> {
-        if let Some((w, _)) = term_size::dimensions() {
-            Box::new(TerminalUI {
-                isatty: atty::is(Stream::Stdout),
-                width: w,
-                x_position: 0,
-            })
-        } else {
-            Box::new(TerminalUI {
-                isatty: false,
-                width: 0,
-                x_position: 0,
-            })
+        let (isatty, width, height) = match term_size::dimensions() {
+            Some((w, h)) => (atty::is(Stream::Stdout), w, h),
+            None => (false, DEFAULT_WIDTH, 0),
+        };
+
+        if isatty {
+            unsafe {
+                libc::signal(libc::SIGWINCH, on_resize as extern "C" fn(libc::c_int) as libc::sighandler_t);
+            }
         }
+
+        Box::new(TerminalUI {
+            isatty,
+            width,
+            x_position: 0,
+            fullscreen: false,
+            height,
+            status_line: false,
+            upper_lines: 0,
+            window: 0,
+            style: 0,
+            lower_row: 1,
+            upper_row: 1,
+            upper_col: 1,
+            editor: LineEditor::new(),
+            interactive: isatty && atty::is(Stream::Stdin),
+            paged_lines: 0,
+            fixed_width: false,
+            theme: Theme::default(),
+            pending_object: None,
+        })
     }
 
     fn clear(&self) {
+        if self.fullscreen {
+            self.enter_alternate_screen();
+        }
+
+        // so the background color fills the whole screen
+        if self.is_term() {
+            self.print_raw(&self.style_codes());
+        }
+
         // Clear screen: ESC [2J
         // Move cursor to 1x1: [H
         if self.is_term() {
@@ -72,79 +403,253 @@ impl UI for TerminalUI {
     }
 
     fn print(&mut self, text: &str) {
+        if self.pending_object.is_some() {
+            self.flush_object(text.starts_with('\n'));
+        }
+
+        // there's no upper window outside of full-screen mode, so anything
+        // printed there (like a v4+ status line) just goes inline
+        if self.window == 1 && self.fullscreen {
+            self.print_upper(text);
+            return;
+        }
+
         if !self.is_term() {
             self.print_raw(text);
             return;
         }
 
+        self.check_resize();
+
         // `.lines()` discards trailing \n and collapses multiple \n's between lines
         let lines = text.split('\n').collect::<Vec<_>>();
         let num_lines = lines.len();
 
         // implements some word-wrapping so words don't get split across lines
-        lines.iter().enumerate().for_each(|(i, line)| {
-            // skip if this line is just the result of a "\n"
-            if !line.is_empty() {
-                // `.split_whitespace` having similar issues as `.lines` above
-                let words = line.split(' ').collect::<Vec<_>>();
-                let num_words = words.len();
-
-                // check that each word can fit on the line before printing it.
-                // if its too big, bump to the next line and reset x-position
-                words.iter().enumerate().for_each(|(i, word)| {
-                    self.x_position += word.len();
-
-                    if self.x_position > self.width {
-                        self.x_position = word.len();
-                        println!();
-                    }
-
-                    print!("{}", word);
-
-                    // add spaces back in if we can (an not on the last element)
-                    if i < num_words - 1 && self.x_position < self.width {
-                        self.x_position += 1;
-                        print!(" ");
-                    }
-                });
-            }
+        for (i, line) in lines.iter().enumerate() {
+            self.print_wrapped(line);
 
             // add newlines back that were removed from split
             if i < num_lines - 1 {
-                println!();
-                self.x_position = 0;
+                self.newline();
             }
-        });
+        }
 
         io::stdout().flush().unwrap();
     }
 
     fn debug(&mut self, text: &str) {
-        self.print(text);
+        if !self.is_term() {
+            self.print(text);
+            return;
+        }
+
+        self.flush_object(false);
+
+        let style = self.theme.debug.clone();
+        self.print_styled(text, &style);
     }
 
-    fn print_object(&mut self, object: &str) {
-        if self.is_term() {
-            self.print_raw("\x1B[37;1m");
+    fn print_object(&mut self, _number: u16, name: &str) {
+        if !self.is_term() {
+            self.print(name);
+            return;
         }
-        self.print(object);
-        if self.is_term() {
-            self.print_raw("\x1B[0m");
+
+        self.flush_object(false);
+
+        if self.window == 0 && self.x_position == 0 {
+            self.pending_object = Some(String::from(name));
+        } else {
+            let style = self.theme.object.clone();
+            self.print_styled(name, &style);
         }
     }
 
-    fn set_status_bar(&self, left: &str, right: &str) {
-        // ESC ]2; "text" BEL
+    fn set_status_bar(&mut self, left: &str, right: &str) {
+        if !self.fullscreen {
+            // ESC ]2; "text" BEL
+            if self.is_term() {
+                self.print_raw(&format!("\x1B]2;{}  -  {}\x07", left, right));
+            }
+            return;
+        }
+
+        // the status line takes the top row once the game starts using it
+        if !self.status_line {
+            self.status_line = true;
+            self.update_scroll_region();
+        }
+
+        self.check_resize();
+
+        let left = format!(" {}", left);
+        let right = format!("{} ", right);
+        let used = left.width() + right.width();
+        let padding = " ".repeat(self.width.saturating_sub(used).max(1));
+        let line = fit_width(&format!("{}{}{}", left, padding, right), self.width);
+
+        // Reverse video: ESC [7m
+        self.move_cursor(1, 1);
+        self.print_raw(&format!("\x1B[0m\x1B[7m{}{}", line, self.style_codes()));
+        self.restore_cursor();
+    }
+
+    fn split_window(&mut self, lines: u16) {
+        // always leave at least one line for the lower window
+        let max = if self.fullscreen {
+            self.height.saturating_sub(self.upper_top())
+        } else {
+            lines as usize
+        };
+
+        self.upper_lines = (lines as usize).min(max);
+
+        if self.upper_row > self.upper_lines {
+            self.upper_row = 1;
+            self.upper_col = 1;
+        }
+
+        if self.fullscreen {
+            self.update_scroll_region();
+        }
+    }
+
+    fn set_window(&mut self, window: u16) {
+        self.flush_object(false);
+        self.window = window;
+
+        // selecting the upper window always puts the cursor at its top left
+        if window == 1 {
+            self.upper_row = 1;
+            self.upper_col = 1;
+        }
+
+        if self.fullscreen {
+            self.restore_cursor();
+        }
+    }
+
+    fn erase_window(&mut self, window: i16) {
+        // -1 unsplits the screen, -1 & -2 clear everything
+        if window == -1 {
+            self.upper_lines = 0;
+            self.window = 0;
+        }
+
+        if !self.fullscreen {
+            return;
+        }
+
+        match window {
+            -1 | -2 => {
+                // the status line gets drawn again before the next read
+                self.print_raw("\x1B[2J");
+                self.lower_row = self.lower_top();
+                self.x_position = 0;
+                self.upper_row = 1;
+                self.upper_col = 1;
+                self.update_scroll_region();
+            }
+            0 => {
+                self.clear_rows(self.lower_top(), self.height);
+                self.lower_row = self.lower_top();
+                self.x_position = 0;
+                self.restore_cursor();
+            }
+            1 if self.upper_lines > 0 => {
+                self.clear_rows(self.upper_top(), self.lower_top() - 1);
+                self.upper_row = 1;
+                self.upper_col = 1;
+                self.restore_cursor();
+            }
+            _ => (),
+        }
+    }
+
+    fn erase_line(&mut self) {
+        // Erase to end of line: ESC [K
+        if self.fullscreen && self.window == 1 {
+            self.print_raw("\x1B[K");
+        }
+    }
+
+    fn set_cursor(&mut self, line: u16, column: u16) {
+        // the cursor can only be moved around in the upper window
+        if self.window != 1 {
+            return;
+        }
+
+        self.upper_row = (line as usize).max(1);
+        self.upper_col = (column as usize).max(1);
+
+        if self.fullscreen {
+            self.restore_cursor();
+        }
+    }
+
+    fn get_cursor(&self) -> (u16, u16) {
+        if self.window == 1 {
+            (self.upper_row as u16, self.upper_col as u16)
+        } else if self.fullscreen {
+            let row = self.lower_row + 1 - self.lower_top();
+            (row as u16, self.x_position as u16 + 1)
+        } else {
+            (1, self.x_position as u16 + 1)
+        }
+    }
+
+    fn set_text_style(&mut self, style: u16) {
+        self.style = style;
+
         if self.is_term() {
-            self.print_raw(&format!("\x1B]2;{}  -  {}\x07", left, right));
+            let codes = self.style_codes();
+            self.print_raw(&codes);
         }
     }
 
-    fn get_user_input(&self) -> String {
+    fn get_user_input(&mut self) -> String {
+        self.check_resize();
+        self.flush_object(false);
+
+        if self.fullscreen {
+            self.window = 0;
+            self.restore_cursor();
+        }
+
         let mut input = String::new();
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Error reading input");
+
+        // the line editor needs a real terminal, otherwise just read lines
+        if self.interactive {
+            self.print_raw(&self.theme.input.escape());
+            let line = self.editor.read_line(self.x_position);
+
+            let codes = self.style_codes();
+            self.print_raw(&codes);
+
+            match line {
+                Some(line) => input = line,
+                // Ctrl-C or Ctrl-D
+                None => {
+                    self.reset();
+                    process::exit(0);
+                }
+            }
+        } else {
+            io::stdin()
+                .read_line(&mut input)
+                .expect("Error reading input");
+        }
+
+        self.paged_lines = 0;
+
+        // the terminal echoes the input (and the newline) on its own
+        if self.fullscreen {
+            let columns = self.x_position + input.trim_end().width();
+            let rows = 1 + columns / self.width.max(1);
+            self.lower_row = (self.lower_row + rows).min(self.height);
+            self.x_position = 0;
+        }
 
         // trim, strip and control sequences that might have gotten in,
         // and then trim once more to get rid of any excess whitespace
@@ -155,8 +660,55 @@ impl UI for TerminalUI {
             .to_string()
     }
 
-    fn reset(&self) {
-        println!();
+    fn get_user_char(&mut self) -> u16 {
+        self.check_resize();
+        self.flush_object(false);
+        io::stdout().flush().unwrap();
+        self.paged_lines = 0;
+
+        if self.interactive {
+            return line_editor::read_zscii_key().unwrap_or_else(|| {
+                self.reset();
+                process::exit(0);
+            });
+        }
+
+        // without a terminal, take the first character of the next line
+        let mut input = String::new();
+        io::stdin()
+            .read_line(&mut input)
+            .expect("Error reading input");
+
+        match input.trim_end_matches(&['\r', '\n'][..]).chars().next() {
+            Some(c) if c.is_ascii() => c as u16,
+            Some(_) => '?' as u16,
+            None => 13,
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.fullscreen {
+            // Reset the scroll region: ESC [r
+            self.print_raw("\x1B[0m\x1B[r");
+            self.end_alternate_screen();
+        } else {
+            if self.is_term() {
+                self.print_raw("\x1B[0m");
+            }
+            println!();
+        }
+    }
+
+    fn get_screen_size(&mut self) -> (usize, usize) {
+        self.check_resize();
+
+        // 255 lines means "infinite" (no paging needed from the game)
+        let height = if self.height == 0 { 255 } else { self.height };
+        (self.width, height)
+    }
+
+    fn set_completions(&mut self, words: Vec<String>, resolution: usize) {
+        self.editor.set_completions(words, resolution);
     }
 
     // unimplemented, only used in web ui
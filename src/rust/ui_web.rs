@@ -1,68 +1,129 @@
 use std::boxed::Box;
-use std::ffi::CString;
-use std::fmt::Write;
 
+use js_sys::Function;
+use serde::Serialize;
 use serde_json;
+use serde_json::Value;
 
-use js_message;
 use traits::UI;
-
-#[derive(Debug)]
+use web::post;
+
+// Printed text goes to js as a list of tokens (the "print" message), which
+// the transcript renders as text nodes, so nothing the game prints can end
+// up as markup:
+//
+//     {"type": "text", "text": "You see a ", "style": []}
+//     {"type": "object", "number": 122, "name": "brass lantern"}
+//     {"type": "newline"}
+//     {"type": "debug", "text": "..."}
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 enum Token {
     Newline,
-    Text(String),
-    Object(String),
-    Debug(String),
+    Text { text: String, style: Vec<&'static str> },
+    Object { number: u16, name: String },
+    Debug { text: String },
 }
 
 #[derive(Debug)]
 pub struct WebUI {
     buffer: Vec<Token>,
+    window: u16,
+    style: u16,
+    on_message: Option<Function>,
 }
 
-impl UI for WebUI {
-    fn new() -> Box<WebUI> {
-        Box::new(WebUI { buffer: Vec::new() })
+impl WebUI {
+    pub fn set_callback(&mut self, on_message: Function) {
+        self.on_message = Some(on_message);
     }
 
-    fn print(&mut self, text: &str) {
-        if text.is_empty() {
-            return;
+    fn post<T: Serialize + ?Sized>(&self, mtype: &str, value: &T) {
+        if let Some(ref callback) = self.on_message {
+            post(callback, mtype, value);
         }
+    }
 
-        if text == "\n" {
-            self.buffer.push(Token::Newline);
-            return;
+    // input only comes in through feed (or feed_char), nothing should ever
+    // wait for it here
+    fn blocking_input_error(&mut self) {
+        self.flush();
+        self.post("error", "The game asked for input outside of a read");
+    }
+
+    // names for the set_text_style bits
+    fn style_names(&self) -> Vec<&'static str> {
+        let names = [(1, "reverse"), (2, "bold"), (4, "italic"), (8, "fixed")];
+
+        names
+            .iter()
+            .filter(|&&(bit, _)| self.style & bit != 0)
+            .map(|&(_, name)| name)
+            .collect()
+    }
+
+    fn push_text(&mut self, text: &str) {
+        let style = self.style_names();
+
+        // runs of text in the same style go in the same token
+        if let Some(&mut Token::Text {
+            text: ref mut last,
+            style: ref last_style,
+        }) = self.buffer.last_mut()
+        {
+            if *last_style == style {
+                last.push_str(text);
+                return;
+            }
         }
 
-        if !text.contains('\n') {
-            self.buffer.push(Token::Text(String::from(text)));
+        self.buffer.push(Token::Text {
+            text: String::from(text),
+            style,
+        });
+    }
+}
+
+impl UI for WebUI {
+    fn new() -> Box<WebUI> {
+        Box::new(WebUI {
+            buffer: Vec::new(),
+            window: 0,
+            style: 0,
+            on_message: None,
+        })
+    }
+
+    fn print(&mut self, text: &str) {
+        // the web ui has no upper window (status goes in the header instead)
+        if text.is_empty() || self.window == 1 {
             return;
         }
 
-        let lines = text.lines().collect::<Vec<_>>();
+        let lines = text.split('\n').collect::<Vec<_>>();
 
         for (index, line) in lines.iter().enumerate() {
-            if !line.is_empty() {
-                self.buffer.push(Token::Text(String::from(*line)));
-            }
-
-            if let Some(_) = lines.get(index + 1) {
+            if index > 0 {
                 self.buffer.push(Token::Newline);
             }
-        }
 
-        if text.ends_with('\n') {
-            self.buffer.push(Token::Newline);
+            if !line.is_empty() {
+                self.push_text(line);
+            }
         }
     }
 
     fn debug(&mut self, text: &str) {
-        self.buffer.push(Token::Debug(String::from(text)));
+        self.buffer.push(Token::Debug {
+            text: String::from(text),
+        });
     }
 
-    fn print_object(&mut self, obj: &str) {
-        self.buffer.push(Token::Object(String::from(obj)));
+    fn print_object(&mut self, number: u16, name: &str) {
+        self.buffer.push(Token::Object {
+            number,
+            name: String::from(name),
+        });
     }
 
     fn flush(&mut self) {
@@ -70,72 +131,56 @@ impl UI for WebUI {
             return;
         }
 
-        let mut html = String::new();
-
-        for (index, item) in self.buffer.iter().enumerate() {
-            let prev = if index == 0 {
-                None
-            } else {
-                self.buffer.get(index - 1)
-            };
-
-            let next = self.buffer.get(index + 1);
-
-            match *item {
-                Token::Newline => {
-                    html.push_str("<br>");
-                }
-                Token::Text(ref text) => {
-                    match prev {
-                        Some(&Token::Text(_)) => (),
-                        _ => html.push_str("<span>"),
-                    }
-
-                    html.push_str(&text);
-
-                    match next {
-                        Some(&Token::Text(_)) => (),
-                        _ => html.push_str("</span>"),
-                    }
-                }
-                Token::Object(ref obj) => {
-                    let class = match (prev, next) {
-                        (None, Some(&Token::Newline)) => "room",
-                        (Some(&Token::Newline), Some(&Token::Newline)) => "room",
-                        _ => "object",
-                    };
-
-                    write!(html, r#"<span class="{}">{}</span>"#, class, obj).unwrap();
-                }
-                Token::Debug(ref text) => {
-                    write!(html, r#"<span class="debug">{}</span>"#, text).unwrap();
-                }
-            }
-        }
-
-        self.message("print", &html);
+        self.post("print", &self.buffer);
         self.buffer.clear();
     }
 
-    fn set_status_bar(&self, left: &str, right: &str) {
-        let msg = serde_json::to_string(&(left, right)).unwrap();
-        self.message("header", &msg)
+    fn set_status_bar(&mut self, left: &str, right: &str) {
+        self.post("header", &(left, right));
     }
 
+    // messages from the zmachine are json (or plain strings), they get
+    // passed on to js as the values they describe
     fn message(&self, mtype: &str, msg: &str) {
-        let type_ptr = CString::new(mtype).unwrap().into_raw();
-        let msg_ptr = CString::new(msg).unwrap().into_raw();
+        let value = serde_json::from_str(msg).unwrap_or_else(|_| Value::String(String::from(msg)));
+        self.post(mtype, &value);
+    }
+
+    fn set_window(&mut self, window: u16) {
+        self.window = window;
+    }
 
-        unsafe {
-            js_message(type_ptr, msg_ptr);
-            CString::from_raw(type_ptr); // free memory
-            CString::from_raw(msg_ptr);
+    fn erase_window(&mut self, window: i16) {
+        if window == -1 {
+            self.window = 0;
         }
     }
 
+    fn set_text_style(&mut self, style: u16) {
+        self.style = style;
+    }
+
+    fn get_cursor(&self) -> (u16, u16) {
+        (1, 1)
+    }
+
+    // text wraps on its own in the browser, so this just needs to be sensible
+    fn get_screen_size(&mut self) -> (usize, usize) {
+        (80, 255)
+    }
+
+    fn split_window(&mut self, _lines: u16) {}
+    fn erase_line(&mut self) {}
+    fn set_cursor(&mut self, _line: u16, _column: u16) {}
+    fn set_completions(&mut self, _words: Vec<String>, _resolution: usize) {}
     fn clear(&self) {}
-    fn reset(&self) {}
-    fn get_user_input(&self) -> String {
-        unimplemented!();
+    fn reset(&mut self) {}
+    fn get_user_input(&mut self) -> String {
+        self.blocking_input_error();
+        String::new()
+    }
+    fn get_user_char(&mut self) -> u16 {
+        self.blocking_input_error();
+        13
     }
 }
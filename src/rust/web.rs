@@ -0,0 +1,304 @@
+// The wasm module's exports (a `ZMachine` class, via wasm-bindgen), driven by
+// src/js/worker.js:
+//
+//     const zvm = new ZMachine(file, (type, value) => ...);
+//     zvm.step();
+//     zvm.feed('open mailbox');
+//     zvm.step();
+//
+// Game output comes back through the message callback as plain js values
+// ("print" tokens, "header" [left, right], "save" [status, base64], ...), and
+// the rest of the state is read with the getters after each step.
+
+use std::cell::RefCell;
+use std::mem;
+use std::panic;
+use std::sync::Once;
+
+use js_sys::{Function, Math};
+use serde::Serialize;
+use serde_wasm_bindgen::Serializer;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+use options::Options;
+use traits::UI;
+use ui_web::WebUI;
+use zmachine::{Event, Zmachine};
+
+#[wasm_bindgen(typescript_custom_section)]
+const TS_TYPES: &str = r#"
+export type MessageType =
+  "print" | "header" | "save" | "savestate" | "restore" | "error";
+
+export type MessageCallback = (type: MessageType, value: any) => void;
+
+export type Token =
+  | { type: "text", text: string, style: ("reverse" | "bold" | "italic" | "fixed")[] }
+  | { type: "object", number: number, name: string }
+  | { type: "newline" }
+  | { type: "debug", text: string };
+
+// "running": the instruction budget ran out, step again to keep going
+// "stuck": no output for a long time, step again or undo
+// "break": stopped in the debugger, feed it $ commands ($step, $continue...)
+export type StepStatus = "input" | "restore" | "running" | "stuck" | "break" | "quit";
+
+export type Room = [number, string];
+
+export interface ObjectEntry {
+  number: number;
+  name: string;
+  parent: number;
+  children: number[];
+  attributes: number[];
+}
+
+// diffs only apply to the update with the version right before them
+export interface ObjectTreeUpdate {
+  type: "full" | "diff";
+  version: number;
+  objects: ObjectEntry[];
+}
+
+export interface HistoryNode {
+  id: number;
+  parent: number | null;
+  children: number[];
+  command: string;
+  location: string;
+  label: string | null;
+}
+
+export interface History {
+  current: number | null;
+  nodes: { [id: string]: HistoryNode };
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "MessageCallback")]
+    pub type MessageCallback;
+
+    #[wasm_bindgen(typescript_type = "StepStatus")]
+    pub type StepStatus;
+
+    #[wasm_bindgen(typescript_type = "Room")]
+    pub type Room;
+
+    #[wasm_bindgen(typescript_type = "ObjectTreeUpdate")]
+    pub type ObjectTreeUpdate;
+
+    #[wasm_bindgen(typescript_type = "ObjectTreeUpdate | null")]
+    pub type MaybeObjectTreeUpdate;
+
+    #[wasm_bindgen(typescript_type = "History")]
+    pub type History;
+}
+
+// there can be any number of machines going at once (each with their own
+// callback), so panics go to the one that was last called into, with its
+// backtrace
+thread_local!(static RUNNING: RefCell<Option<(Function, *const Zmachine)>> = RefCell::new(None););
+
+static PANIC_HOOK: Once = Once::new();
+
+fn to_js<T: Serialize + ?Sized>(value: &T) -> JsValue {
+    // plain objects instead of es6 Maps
+    value
+        .serialize(&Serializer::json_compatible())
+        .expect("Error converting value for js")
+}
+
+pub fn post<T: Serialize + ?Sized>(callback: &Function, mtype: &str, value: &T) {
+    callback
+        .call2(&JsValue::NULL, &JsValue::from_str(mtype), &to_js(value))
+        .expect("Error in js message callback");
+}
+
+#[wasm_bindgen]
+pub struct ZMachine {
+    // (boxed, so the panic hook's pointer stays put when this moves)
+    zvm: Box<Zmachine>,
+    story: Vec<u8>,
+    on_message: Function,
+}
+
+impl ZMachine {
+    fn enter(&self) {
+        let zvm = &*self.zvm as *const Zmachine;
+        RUNNING.with(|cell| *cell.borrow_mut() = Some((self.on_message.clone(), zvm)));
+    }
+}
+
+impl Drop for ZMachine {
+    fn drop(&mut self) {
+        let zvm = &*self.zvm as *const Zmachine;
+
+        RUNNING.with(|cell| {
+            let mut running = cell.borrow_mut();
+            if running.as_ref().map(|&(_, running)| running) == Some(zvm) {
+                *running = None;
+            }
+        });
+    }
+}
+
+#[wasm_bindgen]
+impl ZMachine {
+    #[wasm_bindgen(constructor)]
+    pub fn new(file: Vec<u8>, on_message: MessageCallback) -> ZMachine {
+        let on_message: Function = on_message.unchecked_into();
+
+        PANIC_HOOK.call_once(|| {
+            panic::set_hook(Box::new(|info| {
+                RUNNING.with(|cell| {
+                    if let Some((ref callback, zvm)) = *cell.borrow() {
+                        // (a panic stops the machine for good, partway
+                        // through an instruction, but its frames are
+                        // still there to read)
+                        let backtrace = unsafe { (*zvm).backtrace() };
+                        let msg = format!("{}\n\nZ-machine backtrace:\n{}", info, backtrace);
+                        post(callback, "error", &msg);
+                    }
+                });
+            }));
+        });
+
+        let mut ui = WebUI::new();
+        ui.set_callback(on_message.clone());
+
+        let mut opts = Options::default();
+        opts.rand_seed = [rand(), rand(), rand(), rand()];
+
+        let machine = ZMachine {
+            zvm: Box::new(Zmachine::new(file.clone(), ui, opts)),
+            story: file,
+            on_message,
+        };
+
+        machine.enter();
+        machine
+    }
+
+    // a separate copy of this game as it is right now (for side-by-side
+    // "what if" play), step it to get back to the same prompt
+    pub fn fork(&self, on_message: MessageCallback) -> ZMachine {
+        self.enter();
+        let state = self.zvm.snapshot();
+
+        let mut copy = ZMachine::new(self.story.clone(), on_message);
+        copy.zvm.restore_snapshot(&state).unwrap();
+        copy
+    }
+
+    // runs until the game needs input (or for as many instructions as the
+    // budget allows, so the page stays responsive)
+    pub fn step(&mut self) -> StepStatus {
+        self.enter();
+
+        let status = match self.zvm.step() {
+            Event::Running => "running",
+            Event::Stuck => "stuck",
+            Event::Break => "break",
+            Event::RestoreRequested => "restore",
+            Event::Quit => "quit",
+            _ => "input",
+        };
+
+        self.zvm.ui.flush();
+        self.zvm.update_status_bar();
+        JsValue::from_str(status).unchecked_into()
+    }
+
+    pub fn feed(&mut self, input: &str) {
+        self.enter();
+        self.zvm.handle_input(String::from(input));
+    }
+
+    // base64 save data (or an empty string to cancel the restore, which is
+    // what a bad save does too)
+    pub fn restore(&mut self, data: &str) {
+        self.enter();
+
+        if let Err(err) = self.zvm.restore(data) {
+            self.zvm.ui.print(&format!("\n[{}]\n", err));
+            self.zvm.restore("").unwrap();
+        }
+    }
+
+    pub fn load_savestate(&mut self, data: &str) {
+        self.enter();
+
+        if let Err(err) = self.zvm.load_savestate(data) {
+            self.zvm.ui.print(&format!("\n[{}]\n", err));
+        }
+    }
+
+    // comes back as a "savestate" message
+    pub fn send_savestate(&mut self) {
+        self.enter();
+        self.zvm.send_savestate();
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.enter();
+        self.zvm.undo()
+    }
+
+    pub fn redo(&mut self) -> bool {
+        self.enter();
+        self.zvm.redo()
+    }
+
+    pub fn room(&self) -> Room {
+        self.enter();
+        to_js(&self.zvm.get_current_room()).unchecked_into()
+    }
+
+    // the whole object table
+    pub fn object_tree(&mut self) -> ObjectTreeUpdate {
+        self.enter();
+        to_js(&self.zvm.get_object_tree_snapshot()).unchecked_into()
+    }
+
+    // just what changed since the last update (null if nothing did)
+    pub fn object_tree_update(&mut self) -> MaybeObjectTreeUpdate {
+        self.enter();
+        to_js(&self.zvm.get_object_tree_update()).unchecked_into()
+    }
+
+    pub fn history(&self) -> History {
+        self.enter();
+        to_js(self.zvm.get_history()).unchecked_into()
+    }
+
+    pub fn object_details(&self, number: u16) -> String {
+        self.enter();
+        self.zvm.debug_object_details(number)
+    }
+
+    // instructions per step (0 for no limit)
+    pub fn set_instruction_budget(&mut self, count: usize) {
+        self.zvm.options.instruction_budget = count;
+    }
+
+    // instructions without output before a step comes back "stuck"
+    pub fn set_watchdog_limit(&mut self, count: usize) {
+        self.zvm.options.watchdog_limit = count;
+    }
+
+    pub fn set_instruction_logs(&mut self, enabled: bool) {
+        self.zvm.options.log_instructions = enabled;
+    }
+
+    // instructions run since the last call (if logging is enabled)
+    pub fn take_instructions(&mut self) -> String {
+        mem::take(&mut self.zvm.instr_log)
+    }
+}
+
+fn rand() -> u32 {
+    (Math::random() * f64::from(0xFFFF)) as u32
+}
@@ -1,12 +1,12 @@
 
 use std::boxed::Box;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
 use std::fmt;
 use std::fmt::Write as FmtWrite;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::str;
 
@@ -17,14 +17,17 @@ use rand::{Rng, SeedableRng};
 use serde_json;
 
 use buffer::Buffer;
+use debuginfo::DebugInfo;
 use frame::Frame;
+use history::{Checkpoint, History, State};
 use instruction::Branch;
 use instruction::Instruction;
 use instruction::Opcode;
 use instruction::Operand;
 use instruction::OperandType;
 use options::Options;
-use quetzal::QuetzalSave;
+use profiler::Profile;
+use quetzal::{QuetzalHistory, QuetzalSave};
 use traits::UI;
 
 #[derive(Debug)]
@@ -44,6 +47,14 @@ pub struct Object {
 
 impl Object {
     fn new(number: u16, zvm: &Zmachine) -> Box<Object> {
+        Box::new(Object {
+            number,
+            name: Object::display_name(number, zvm),
+            children: Vec::new(),
+        })
+    }
+
+    fn display_name(number: u16, zvm: &Zmachine) -> String {
         let mut name = if number > 0 {
             zvm.get_object_name(number)
         } else {
@@ -54,11 +65,24 @@ impl Object {
             name += "(No Name)";
         }
 
-        Box::new(Object {
-            number,
-            name,
-            children: Vec::new(),
-        })
+        name
+    }
+
+    fn find(&self, number: u16) -> Option<&Object> {
+        if self.number == number {
+            return Some(self);
+        }
+
+        self.children.iter().filter_map(|child| child.find(number)).next()
+    }
+
+    // names of the direct children, leaving out nameless objects
+    fn child_names(&self, except: u16) -> Vec<String> {
+        self.children
+            .iter()
+            .filter(|child| child.number != except && child.name != "(No Name)")
+            .map(|child| child.name.clone())
+            .collect()
     }
 
     fn print_tree(&self, indent: &str, mut depth: u8, is_last: bool) -> String {
@@ -104,6 +128,92 @@ impl fmt::Display for Object {
     }
 }
 
+/// One object from the object table, with its children in order
+#[derive(Debug, Serialize)]
+pub struct ObjectEntry {
+    number: u16,
+    name: String,
+    parent: u16,
+    children: Vec<u16>,
+    attributes: Vec<u16>,
+}
+
+/// Changes to the object table since the last `get_object_tree_update`.
+/// Diffs only apply on top of the update with the previous version number.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TreeUpdate {
+    /// Every object in the table
+    Full { version: u32, objects: Vec<ObjectEntry> },
+    /// Just the objects whose links or attributes changed
+    Diff { version: u32, objects: Vec<ObjectEntry> },
+}
+
+/// Why `Zmachine::run_until_event` stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A read instruction wants a line of input (answer with `feed`)
+    LineInput,
+    /// A read_char instruction wants a single key (answer with `feed_char`)
+    CharInput,
+    /// The game wants to save: store `save_data()` then call `finish_save`
+    SaveRequested,
+    /// The game wants a save back: call `finish_restore`
+    RestoreRequested,
+    /// The game is over
+    Quit,
+    /// Ran through `Options::instruction_budget` instructions without
+    /// stopping: call `run_until_event` again to keep going
+    Running,
+    /// Ran through `Options::watchdog_limit` instructions without any output
+    /// or input (probably an infinite loop): keep going with
+    /// `run_until_event`, or `undo` back to the start of the turn
+    Stuck,
+    /// Stopped at a breakpoint (or after a `$step`, `$next` or `$finish`):
+    /// give debug commands to `feed` until one of them resumes the game
+    Break,
+}
+
+// how far a $step, $next or $finish runs before stopping again
+#[derive(Debug, Clone, Copy)]
+enum Stepping {
+    Step,
+    // in the same routine (or one it returns to)
+    Next(usize),
+    // once the routine returns
+    Finish(usize),
+}
+
+// something a $watch keeps an eye on
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Watch {
+    Global(u8),
+    // a word
+    Memory(usize),
+    Attr(u16, u16),
+    Parent(u16),
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Watch::Global(index) => write!(f, "g{}", index),
+            Watch::Memory(addr) => write!(f, "mem {:x}", addr),
+            Watch::Attr(obj, attr) => write!(f, "attr {} {}", obj, attr),
+            Watch::Parent(obj) => write!(f, "parent {}", obj),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Watchpoint {
+    watch: Watch,
+    // as of the last write to it
+    value: u16,
+    // break on a change, instead of just logging it
+    stop: bool,
+}
+
 #[derive(Debug)]
 struct ObjectProperty {
     num: u8,
@@ -148,9 +258,30 @@ pub struct Zmachine {
     obj_size: usize,
     attr_width: usize,
     paused_instr: Option<Instruction>,
-    current_state: Option<(String, Vec<u8>)>,
-    undos: Vec<(String, Vec<u8>)>,
-    redos: Vec<(String, Vec<u8>)>,
+    // instructions since the last output or input (for the watchdog)
+    quiet_instrs: usize,
+    // debugger: addresses to stop at (an instruction, or the start of a
+    // routine), and whether it's stopped right now. resuming runs the
+    // instruction it stopped at without checking it again
+    breakpoints: BTreeSet<usize>,
+    stepping: Option<Stepping>,
+    stopped: bool,
+    skip_break: bool,
+    watches: Vec<Watchpoint>,
+    // the instruction running while watches are set, and its routine (None
+    // for main), so a write gets blamed on it even after a call or return
+    watch_writer: Option<(usize, Option<usize>)>,
+    watch_break: bool,
+    // names from an Inform debug file, if one was loaded
+    debug_info: DebugInfo,
+    profile: Option<Profile>,
+    // objects whose links or attributes were written since the last tree
+    // update (None when the whole table was replaced: restore, undo, restart)
+    object_changes: Option<BTreeSet<u16>>,
+    tree_version: u32,
+    history: History,
+    pending_input: Option<String>,
+    checkpoints: Vec<Checkpoint>,
     rng: rand::XorShiftRng,
 }
 
@@ -192,9 +323,24 @@ impl Zmachine {
             obj_size: if version <= 3 { 9 } else { 14 },
             attr_width: if version <= 3 { 4 } else { 6 },
             paused_instr: None,
-            current_state: None,
-            undos: Vec::new(),
-            redos: Vec::new(),
+            quiet_instrs: 0,
+            breakpoints: BTreeSet::new(),
+            stepping: None,
+            stopped: false,
+            skip_break: false,
+            watches: Vec::new(),
+            watch_writer: None,
+            watch_break: false,
+            debug_info: DebugInfo::empty(),
+            profile: None,
+            object_changes: None,
+            tree_version: 0,
+            history: History::new(
+                memory.slice(0, static_start).to_vec(),
+                options.undo_budget,
+            ),
+            pending_input: None,
+            checkpoints: Vec::new(),
             rng: rand::SeedableRng::from_seed(options.rand_seed.clone()),
             memory,
             options,
@@ -203,9 +349,38 @@ impl Zmachine {
         // read into dictionary & word separators
         zvm.populate_dictionary();
 
+        zvm.update_screen_size();
+
+        // words for tab-completion in the terminal
+        let words = zvm.dictionary.keys().cloned().collect();
+        let resolution = if zvm.version <= 3 { 6 } else { 9 };
+        zvm.ui.set_completions(words, resolution);
+
+        if !zvm.options.profile_file.is_empty() {
+            zvm.start_profile();
+        }
+
         zvm
     }
 
+    /// Loads the names from an Inform debug information file (gameinfo.dbg,
+    /// in the XML format) for the debug commands, disassembly and backtraces
+    pub fn load_debug_info(&mut self, data: &[u8]) -> Result<(), String> {
+        let info = DebugInfo::parse(data, self.globals_addr)?;
+
+        // (just the release number and serial code, other header bytes can
+        // change as the game runs)
+        if info.prefix.len() >= 0x18
+            && (info.prefix[0x02..0x04] != *self.memory.read(0x02, 2)
+                || info.prefix[0x12..0x18] != *self.memory.read(0x12, 6))
+        {
+            return Err(String::from("The debug file is for a different story file"));
+        }
+
+        self.debug_info = info;
+        Ok(())
+    }
+
     #[allow(dead_code)]
     fn calculate_checksum(memory: &Buffer) -> u16 {
         let mut sum: usize = 0;
@@ -296,6 +471,7 @@ impl Zmachine {
 
         let addr = self.globals_addr + index as usize * 2;
         self.memory.write_word(addr, value);
+        self.check_watches(addr, 2);
     }
 
     fn read_local(&self, index: u8) -> u16 {
@@ -383,9 +559,9 @@ impl Zmachine {
 
         let offset = 2 * index as usize;
         let word_addr = self.memory.read_word(self.abbrev_table + offset);
-        let addr = word_addr * 2; // "Word addresses are used only in the abbreviations table" - 1.2.2
+        let addr = word_addr as usize * 2; // "Word addresses are used only in the abbreviations table" - 1.2.2
 
-        self.read_zstring_from_abbrev(addr as usize)
+        self.read_zstring_from_abbrev(addr)
     }
 
     fn read_zstring_from_abbrev(&self, addr: usize) -> String {
@@ -459,6 +635,55 @@ impl Zmachine {
         zstring
     }
 
+    // whether the string at addr (and every abbreviation in it) ends inside
+    // the story file, so read_zstring can't run off the end
+    fn zstring_fits(&self, addr: usize, allow_abbrevs: bool) -> bool {
+        let mut zchars = Vec::new();
+        let mut addr = addr;
+
+        loop {
+            if addr + 1 >= self.memory.len() {
+                return false;
+            }
+
+            let word = self.memory.read_word(addr);
+            addr += 2;
+
+            zchars.push(((word >> 10) & 0b0001_1111) as u8);
+            zchars.push(((word >> 5) & 0b0001_1111) as u8);
+            zchars.push((word & 0b0001_1111) as u8);
+
+            if word & 0x8000 != 0 {
+                break;
+            }
+        }
+
+        let mut index = 0;
+
+        while index < zchars.len() {
+            match (zchars[index], zchars.get(index + 1)) {
+                (zch @ 1..=3, Some(&next)) => {
+                    let entry = self.abbrev_table + 2 * ((zch - 1) * 32 + next) as usize;
+
+                    if !allow_abbrevs || entry + 1 >= self.memory.len() {
+                        return false;
+                    }
+
+                    if !self.zstring_fits(self.memory.read_word(entry) as usize * 2, false) {
+                        return false;
+                    }
+
+                    index += 2;
+                }
+                // (a 10 bit character)
+                (5, Some(&6)) => index += 4,
+                _ => index += 1,
+            }
+        }
+
+        true
+    }
+
     // reads the ENCODED byte length of a zstring, how many consecutive
     // bytes in memory it is (not just the number of characters)
     fn zstring_length(&self, addr: usize) -> usize {
@@ -544,6 +769,8 @@ impl Zmachine {
             write.byte(len as u8);
             write.byte(token_addr as u8);
         });
+
+        self.check_watches(parse_addr + 1, 1 + tokens.len() * 4);
     }
 
     fn get_object_addr(&self, object: u16) -> usize {
@@ -594,6 +821,7 @@ impl Zmachine {
     }
 
     fn set_parent(&mut self, object: u16, parent: u16) {
+        self.mark_object(object);
         let addr = self.get_object_addr(object) + self.attr_width;
 
         if self.version <= 3 {
@@ -601,6 +829,8 @@ impl Zmachine {
         } else {
             self.memory.write_word(addr, parent);
         }
+
+        self.check_watches(addr, 2);
     }
 
     fn get_sibling(&self, object: u16) -> u16 {
@@ -618,12 +848,18 @@ impl Zmachine {
     }
 
     fn set_sibling(&mut self, object: u16, sibling: u16) {
+        // (the parent's list of children changes too)
+        let parent = self.get_parent(object);
+        self.mark_object(parent);
+        self.mark_object(object);
         let addr = self.get_object_addr(object) + self.attr_width;
 
         if self.version <= 3 {
             self.memory.write_byte(addr + 1, sibling as u8);
+            self.check_watches(addr + 1, 1);
         } else {
             self.memory.write_word(addr + 2, sibling);
+            self.check_watches(addr + 2, 2);
         }
     }
 
@@ -642,12 +878,15 @@ impl Zmachine {
     }
 
     fn set_child(&mut self, object: u16, child: u16) {
+        self.mark_object(object);
         let addr = self.get_object_addr(object) + self.attr_width;
 
         if self.version <= 3 {
             self.memory.write_byte(addr + 2, child as u8);
+            self.check_watches(addr + 2, 1);
         } else {
             self.memory.write_word(addr + 4, child);
+            self.check_watches(addr + 4, 2);
         }
     }
 
@@ -709,6 +948,30 @@ impl Zmachine {
         self.set_sibling(object, parents_first_child);
     }
 
+    fn mark_object(&mut self, object: u16) {
+        if let Some(ref mut changes) = self.object_changes {
+            changes.insert(object);
+        }
+    }
+
+    // after a write by address (storew, storeb, $poke...) that could have
+    // landed in an object's entry without going through set_parent & co
+    fn memory_written(&mut self, addr: usize, len: usize) {
+        if self.object_changes.is_none() {
+            return;
+        }
+
+        // (object entries run up to the first property table)
+        let table_end = self.get_object_prop_table_addr(1);
+
+        for addr in addr..addr + len {
+            if addr >= self.obj_table_addr && addr < table_end {
+                let object = (addr - self.obj_table_addr) / self.obj_size + 1;
+                self.mark_object(object as u16);
+            }
+        }
+    }
+
     fn get_total_object_count(&self) -> u16 {
         // by convention, the property table for object #1 is located AFTER
         // the last object in the object table:
@@ -716,7 +979,7 @@ impl Zmachine {
         let obj_size = self.attr_width + if self.version <= 3 { 3 } else { 9 } + 2;
 
         // v1-3 have a max of 255 objects, v4+ can have up to 65535
-        ((obj_table_end - self.obj_table_addr) / obj_size) as u16
+        (obj_table_end.saturating_sub(self.obj_table_addr) / obj_size) as u16
     }
 
     fn add_object_children(&self, parent: &mut Object) {
@@ -737,6 +1000,69 @@ impl Zmachine {
         }
     }
 
+    fn get_object_entry(&self, number: u16) -> ObjectEntry {
+        let mut children = Vec::new();
+        let mut next = self.get_child(number);
+
+        while next > 0 {
+            children.push(next);
+            next = self.get_sibling(next);
+        }
+
+        let attributes = (0..(self.attr_width * 8) as u16)
+            .filter(|&attr| self.test_attr(number, attr) == 1)
+            .collect();
+
+        ObjectEntry {
+            number,
+            name: Object::display_name(number, self),
+            parent: self.get_parent(number),
+            children,
+            attributes,
+        }
+    }
+
+    // Web UI only - the objects changed since the last update (or None if
+    // nothing did), which is a lot less than the whole tree every turn
+    #[allow(dead_code)]
+    pub fn get_object_tree_update(&mut self) -> Option<TreeUpdate> {
+        let changes = match self.object_changes.take() {
+            None => return Some(self.get_object_tree_snapshot()),
+            Some(changes) => changes,
+        };
+
+        self.object_changes = Some(BTreeSet::new());
+
+        if changes.is_empty() {
+            return None;
+        }
+
+        self.tree_version += 1;
+
+        Some(TreeUpdate::Diff {
+            version: self.tree_version,
+            objects: changes
+                .into_iter()
+                .filter(|&number| number > 0)
+                .map(|number| self.get_object_entry(number))
+                .collect(),
+        })
+    }
+
+    // Web UI only - the whole object table (later diffs build on this one)
+    #[allow(dead_code)]
+    pub fn get_object_tree_snapshot(&mut self) -> TreeUpdate {
+        self.object_changes = Some(BTreeSet::new());
+        self.tree_version += 1;
+
+        TreeUpdate::Full {
+            version: self.tree_version,
+            objects: (1..self.get_total_object_count() + 1)
+                .map(|number| self.get_object_entry(number))
+                .collect(),
+        }
+    }
+
     pub fn get_object_tree(&self) -> Object {
         // start using the INVALID_OBJECT 0 as the root
         let mut root = Object::new(0, self);
@@ -789,11 +1115,13 @@ impl Zmachine {
             panic!("Can't set out-of-bounds attribute: {}", attr);
         }
 
+        self.mark_object(object);
         let addr = self.get_object_addr(object) + attr as usize / 8;
         let byte = self.memory.read_byte(addr);
         let bit = attr % 8;
 
         self.memory.write_byte(addr, byte | (128 >> bit));
+        self.check_watches(addr, 1);
     }
 
     fn clear_attr(&mut self, object: u16, attr: u16) {
@@ -801,11 +1129,13 @@ impl Zmachine {
             panic!("Can't clear out-of-bounds attribute: {}", attr);
         }
 
+        self.mark_object(object);
         let addr = self.get_object_addr(object) + attr as usize / 8;
         let byte = self.memory.read_byte(addr);
         let bit = attr % 8;
 
         self.memory.write_byte(addr, byte & !(128 >> bit));
+        self.check_watches(addr, 1);
     }
 
     fn get_default_prop(&self, property_number: u16) -> u16 {
@@ -939,9 +1269,13 @@ impl Zmachine {
         } else {
             self.memory.write_word(prop.addr, value);
         }
+
+        self.check_watches(prop.addr, if prop.len == 1 { 1 } else { 2 });
+
+        self.mark_object(object);
     }
 
-    // Web UI only
+    // Web UI / JSON mode only
     #[allow(dead_code)]
     pub fn get_current_room(&self) -> (u16, String) {
         let num = self.read_global(0);
@@ -950,6 +1284,21 @@ impl Zmachine {
         (num, name)
     }
 
+    // JSON mode only
+    // (inventory, other things in the room) from the object tree
+    #[allow(dead_code)]
+    pub fn get_surroundings(&self) -> (Vec<String>, Vec<String>) {
+        let tree = self.get_object_tree();
+        let you = self.find_yourself().unwrap_or(0);
+
+        let inventory = tree.find(you).map_or_else(Vec::new, |obj| obj.child_names(0));
+        let objects = tree
+            .find(self.read_global(0))
+            .map_or_else(Vec::new, |room| room.child_names(you));
+
+        (inventory, objects)
+    }
+
     fn get_status(&self) -> (String, String) {
         let num = self.read_global(0);
         let left = self.get_object_name(num);
@@ -976,7 +1325,27 @@ impl Zmachine {
         (left, right)
     }
 
-    pub fn update_status_bar(&self) {
+    // lets the game know how big the screen is (it can change between turns)
+    fn update_screen_size(&mut self) {
+        if self.version < 4 {
+            return;
+        }
+
+        let (width, height) = self.ui.get_screen_size();
+
+        self.memory.write_byte(0x20, height.min(255) as u8);
+        self.memory.write_byte(0x21, width.min(255) as u8);
+
+        // v5+ measures in "units", which are just characters here
+        if self.version >= 5 {
+            self.memory.write_word(0x22, width.min(0xFFFF) as u16);
+            self.memory.write_word(0x24, height.min(0xFFFF) as u16);
+            self.memory.write_byte(0x26, 1);
+            self.memory.write_byte(0x27, 1);
+        }
+    }
+
+    pub fn update_status_bar(&mut self) {
         // status bar only used in v1-3
         if self.version > 3 {
             return;
@@ -998,21 +1367,147 @@ impl Zmachine {
         QuetzalSave::make(pc, dynamic, original, frames, chksum, release, serial)
     }
 
-    fn restore_state(&mut self, data: &[u8]) {
-        let save = QuetzalSave::from_bytes(&data[..], &self.original_dynamic[..]);
+    // an uncompressed state for the undo history
+    fn make_history_state(&self, pc: usize) -> State {
+        State {
+            pc,
+            frames: QuetzalSave::make_stks_body(&self.frames),
+            memory: self.memory.slice(0, self.static_start).to_vec(),
+        }
+    }
+
+    // (whatever instruction was paused doesn't apply to the new state)
+    fn restore_history_state(&mut self, state: State) {
+        self.pc = state.pc;
+        self.frames = QuetzalSave::read_frames(&state.frames);
+        self.memory.write(0, state.memory.as_slice());
+        self.paused_instr = None;
+        self.object_changes = None;
+        self.stop_debugging();
+    }
+
+    // a different state doesn't pick up where the debugger stopped
+    fn stop_debugging(&mut self) {
+        self.stepping = None;
+        self.stopped = false;
+        self.skip_break = false;
+    }
+
+    // turns a state from the undo history into a regular save file
+    fn export_history_state(&self, state: &State) -> Vec<u8> {
+        let frames = QuetzalSave::read_frames(&state.frames);
+        let chksum = self.memory.read_word(0x1c);
+        let release = self.memory.read_word(0x02);
+        let serial = self.memory.read(0x12, 6);
+
+        QuetzalSave::make(
+            state.pc,
+            &state.memory,
+            &self.original_dynamic,
+            &frames,
+            chksum,
+            release,
+            serial,
+        )
+    }
+
+    // verify that the save is for the right game and that the memory is ok
+    fn read_save(&self, data: &[u8]) -> Result<QuetzalSave, String> {
+        let save = QuetzalSave::try_from_bytes(data, &self.original_dynamic)
+            .ok_or("Invalid save, it's damaged or not a Quetzal save")?;
 
-        // verify that the save if so the right game and that the memory is ok
         if save.chksum != self.memory.read_word(0x1C) {
-            panic!("Invalid save, checksum is different");
+            return Err(String::from("Invalid save, checksum is different"));
         }
 
         if self.static_start < save.memory.len() {
-            panic!("Invalid save, memory is too long");
+            return Err(String::from("Invalid save, memory is too long"));
         }
 
+        Ok(save)
+    }
+
+    // (leaves everything alone if the save is no good)
+    fn restore_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let save = self.read_save(data)?;
+
         self.pc = save.pc;
         self.frames = save.frames;
         self.memory.write(0, save.memory.as_slice());
+        self.paused_instr = None;
+        self.object_changes = None;
+        self.stop_debugging();
+
+        Ok(())
+    }
+
+    // undo history is kept next to the save file (ex: "zork.sav" => "zork.undo")
+    fn history_path(save_path: &Path) -> PathBuf {
+        save_path.with_extension("undo")
+    }
+
+    fn save_history(&mut self, save_path: &Path) {
+        let chksum = self.memory.read_word(0x1C);
+        let release = self.memory.read_word(0x02);
+        let serial = self.memory.read(0x12, 6).to_vec();
+        let original = self.original_dynamic.clone();
+
+        let export = |state: &State| {
+            let frames = QuetzalSave::read_frames(&state.frames);
+            QuetzalSave::make(state.pc, &state.memory, &original, &frames, chksum, release, &serial)
+        };
+
+        // only keep the most recent turns around
+        let (entries, current) = self.history.to_entries(self.options.undo_limit, export);
+        let history = QuetzalHistory { entries, current };
+        let data = history.make(chksum, release, &serial);
+
+        // failing to write the history shouldn't stop the save itself
+        if let Ok(mut file) = File::create(Zmachine::history_path(save_path)) {
+            file.write_all(&data[..]).ok();
+        }
+    }
+
+    fn load_history(&mut self, save_path: &Path) {
+        let mut data = Vec::new();
+
+        if let Ok(mut file) = File::open(Zmachine::history_path(save_path)) {
+            if file.read_to_end(&mut data).is_err() {
+                return;
+            }
+        } else {
+            return;
+        }
+
+        let chksum = self.memory.read_word(0x1C);
+
+        if let Some(history) = QuetzalHistory::from_bytes(&data[..], chksum) {
+            let original = self.original_dynamic.clone();
+            let story_length = self.memory.len();
+
+            // (one bad state and the whole history gets dropped)
+            let import = |save_data: &[u8]| {
+                QuetzalSave::try_from_bytes(save_data, &original)
+                    .filter(|save| save.pc < story_length)
+                    .map(|save| State {
+                        pc: save.pc,
+                        frames: QuetzalSave::make_stks_body(&save.frames),
+                        memory: save.memory,
+                    })
+            };
+
+            let history = History::from_entries(
+                history.entries,
+                history.current,
+                original.clone(),
+                self.options.undo_budget,
+                import,
+            );
+
+            if let Some(history) = history {
+                self.history = history;
+            }
+        }
     }
 
     pub fn undo(&mut self) -> bool {
@@ -1022,18 +1517,23 @@ impl Zmachine {
             }
         }
 
-        if self.undos.is_empty() {
-            self.ui.print("\n[Can't undo that far.]\n");
-            return false;
+        // stopped in the middle of a turn (Running / Stuck), so go back to
+        // the start of it, before the input that set it off
+        if self.paused_instr.is_none() {
+            if let Some(id) = self.history.current().map(|node| node.id) {
+                let state = self.history.materialize(id);
+                self.restore_history_state(state);
+                return true;
+            }
         }
 
-        let new_current = self.undos.pop().unwrap();
-        self.redos.push(self.current_state.take().unwrap());
-
-        self.restore_state(new_current.1.as_slice());
-        self.current_state = Some(new_current);
-
-        true
+        if let Some(state) = self.history.undo() {
+            self.restore_history_state(state);
+            true
+        } else {
+            self.ui.print("\n[Can't undo that far.]\n");
+            false
+        }
     }
 
     pub fn redo(&mut self) -> bool {
@@ -1043,20 +1543,65 @@ impl Zmachine {
             }
         }
 
-        if self.redos.is_empty() {
+        if let Some(state) = self.history.redo() {
+            self.restore_history_state(state);
+            true
+        } else {
             self.ui.print("\n[Nothing to redo.]\n");
-            return false;
+            false
+        }
+    }
+
+    // moves to another branch of the undo history (by number or label)
+    pub fn switch_timeline(&mut self, name: &str) -> bool {
+        if let Some(ref instr) = self.paused_instr {
+            if instr.opcode != Opcode::VAR_228 {
+                return false;
+            }
+        }
+
+        let state = self.history.find(name).and_then(|id| self.history.switch(id));
+
+        if let Some(state) = state {
+            self.restore_history_state(state);
+            true
+        } else {
+            self.ui.print("\n[Can't find that point in the history.]\n");
+            false
+        }
+    }
+
+    // jumps to a named checkpoint (as a new turn, so it can be undone)
+    pub fn goto_checkpoint(&mut self, name: &str) -> bool {
+        if let Some(ref instr) = self.paused_instr {
+            if instr.opcode != Opcode::VAR_228 {
+                return false;
+            }
         }
 
-        let new_current = self.redos.pop().unwrap();
-        self.undos.push(self.current_state.take().unwrap());
+        let data = match self.checkpoints.iter().find(|mark| mark.name == name) {
+            Some(mark) => mark.data.clone(),
+            None => {
+                self.ui.print("\n[Can't find that checkpoint.]\n");
+                return false;
+            }
+        };
+
+        self.restore_state(&data).unwrap();
 
-        self.restore_state(new_current.1.as_slice());
-        self.current_state = Some(new_current);
+        let location = self.get_object_name(self.read_global(0));
+        let state = self.make_history_state(self.pc);
+        self.history.push(&format!("$goto {}", name), location, state);
 
         true
     }
 
+    // Web UI only
+    #[allow(dead_code)]
+    pub fn get_history(&self) -> &History {
+        &self.history
+    }
+
     fn get_arguments(&mut self, operands: &[Operand]) -> Vec<u16> {
         operands
             .iter()
@@ -1072,6 +1617,10 @@ impl Zmachine {
         let frame = self.frames.pop().expect("Can't pop off last frame!");
         self.pc = frame.resume;
 
+        if let Some(ref mut profile) = self.profile {
+            profile.returned(self.frames.len());
+        }
+
         if let Some(index) = frame.store {
             self.write_variable(index, value);
         }
@@ -1113,38 +1662,42 @@ impl Zmachine {
     }
 
     fn decode_instruction(&self, addr: usize) -> Instruction {
+        match self.try_decode_instruction(addr) {
+            Some(instr) => instr,
+            None => panic!("Opcode not found @ {:x}", addr),
+        }
+    }
+
+    // (None if there isn't a valid opcode there, for the disassembler)
+    fn try_decode_instruction(&self, addr: usize) -> Option<Instruction> {
+        if addr >= self.memory.len() {
+            return None;
+        }
+
         let mut read = self.memory.get_reader(addr);
         let first = read.byte();
 
         let btm_4 = |num| num & 0b0000_1111;
         let btm_5 = |num| num & 0b0001_1111;
         let get_types = |bytes: &[u8]| OperandType::from(bytes);
-
-        let get_opcode = |code: u8, offset: u16| {
-            let num = u16::from(code) + offset;
-
-            match Opcode::from_u16(num) {
-                Some(val) => val,
-                None => panic!("Opcode not found: {:?}", num),
-            }
-        };
+        let get_opcode = |code: u8, offset: u16| Opcode::from_u16(u16::from(code) + offset);
 
         use self::OperandType::*;
 
         #[allow(unreachable_patterns)]
         let (opcode, optypes) = match first {
-            0xbe => (get_opcode(read.byte(), 1000), get_types(&[read.byte()])),
-            0x00...0x1f => (get_opcode(btm_5(first), 0), vec![Small, Small]),
-            0x20...0x3f => (get_opcode(btm_5(first), 0), vec![Small, Variable]),
-            0x40...0x5f => (get_opcode(btm_5(first), 0), vec![Variable, Small]),
-            0x60...0x7f => (get_opcode(btm_5(first), 0), vec![Variable, Variable]),
-            0x80...0x8f => (get_opcode(btm_4(first), 128), vec![Large]),
-            0x90...0x9f => (get_opcode(btm_4(first), 128), vec![Small]),
-            0xa0...0xaf => (get_opcode(btm_4(first), 128), vec![Variable]),
-            0xb0...0xbd | 0xbf => (get_opcode(btm_4(first), 176), vec![]), // OP_0
-            0xc0...0xdf => (get_opcode(btm_5(first), 0), get_types(&[read.byte()])),
+            0xbe => (get_opcode(read.byte(), 1000)?, get_types(&[read.byte()])),
+            0x00...0x1f => (get_opcode(btm_5(first), 0)?, vec![Small, Small]),
+            0x20...0x3f => (get_opcode(btm_5(first), 0)?, vec![Small, Variable]),
+            0x40...0x5f => (get_opcode(btm_5(first), 0)?, vec![Variable, Small]),
+            0x60...0x7f => (get_opcode(btm_5(first), 0)?, vec![Variable, Variable]),
+            0x80...0x8f => (get_opcode(btm_4(first), 128)?, vec![Large]),
+            0x90...0x9f => (get_opcode(btm_4(first), 128)?, vec![Small]),
+            0xa0...0xaf => (get_opcode(btm_4(first), 128)?, vec![Variable]),
+            0xb0...0xbd | 0xbf => (get_opcode(btm_4(first), 176)?, vec![]), // OP_0
+            0xc0...0xdf => (get_opcode(btm_5(first), 0)?, get_types(&[read.byte()])),
             0xe0...0xff => {
-                let opcode = get_opcode(btm_5(first), 224);
+                let opcode = get_opcode(btm_5(first), 224)?;
 
                 if opcode == Opcode::VAR_236 || opcode == Opcode::VAR_250 {
                     (opcode, get_types(&[read.byte(), read.byte()]))
@@ -1183,7 +1736,7 @@ impl Zmachine {
 
             // the offset (if two bytes) is a 14 bit unsigned int: 2^14 = 16384
             let address = if offset > (16384 / 2) {
-                Some(read.position() + offset - 16384 - 2)
+                Some((read.position() + offset).checked_sub(16384 + 2)?)
             } else {
                 Some(read.position() + offset - 2)
             };
@@ -1209,7 +1762,12 @@ impl Zmachine {
             None
         };
 
+        // (the text has to end inside the story, for the disassembler's guesses)
         let text = if Instruction::does_text(opcode) {
+            if !self.zstring_fits(read.position(), true) {
+                return None;
+            }
+
             Some(self.read_zstring(read.position()))
         } else {
             None
@@ -1224,7 +1782,7 @@ impl Zmachine {
         let name = Instruction::name(opcode, self.version);
         let next = read.position() + text_length;
 
-        Instruction {
+        Some(Instruction {
             addr,
             opcode,
             name,
@@ -1233,10 +1791,10 @@ impl Zmachine {
             branch,
             text,
             next,
-        }
+        })
     }
 
-    pub fn handle_instruction(&mut self, instr: &Instruction) {
+    fn handle_instruction(&mut self, instr: &Instruction) {
         use self::Opcode::*;
 
         // ~mutably~ gets the arguments (might pop stack)
@@ -1317,8 +1875,6 @@ impl Zmachine {
             (OP0_177, _) => self.do_rfalse(),
             (OP0_178, _) => self.do_print(instr),
             (OP0_179, _) => self.do_print_ret(instr),
-            (OP0_181, _) => self.do_save(instr),
-            (OP0_182, _) => self.do_restore(instr),
             (OP0_183, _) => self.do_restart(),
             (OP0_184, _) => self.do_ret_popped(),
             (OP0_185, _) => self.do_pop(),
@@ -1328,18 +1884,25 @@ impl Zmachine {
             (VAR_225, &[array, index, value]) => self.do_storew(array, index, value),
             (VAR_226, &[array, index, value]) => self.do_storeb(array, index, value),
             (VAR_227, &[obj, prop, value]) => self.do_put_prop(obj, prop, value),
-            (VAR_228, &[text, parse]) => self.do_sread(instr, text, parse),
             (VAR_229, &[chr]) => self.do_print_char(chr),
             (VAR_230, &[num]) => self.do_print_num(num),
             (VAR_232, &[value]) => self.do_push(value),
             (VAR_233, &[var]) => { self.do_pull(var); }
+            (VAR_234, &[lines]) => self.ui.split_window(lines),
+            (VAR_235, &[window]) => self.ui.set_window(window),
+            (VAR_237, &[window]) => self.ui.erase_window(window as i16),
+            (VAR_238, _) => self.ui.erase_line(),
+            (VAR_239, &[line, column]) => self.ui.set_cursor(line, column),
+            (VAR_239, &[line, column, _]) => self.ui.set_cursor(line, column), // v6 window arg
+            (VAR_240, &[array]) => self.do_get_cursor(array),
+            (VAR_241, &[style]) => self.ui.set_text_style(style),
             (VAR_236, _) if !args.is_empty() => self.do_call(instr, args[0], &args[1..]), // call_vs2
             (VAR_249, _) if !args.is_empty() => self.do_call(instr, args[0], &args[1..]), // call_vn
             (VAR_250, _) if !args.is_empty() => self.do_call(instr, args[0], &args[1..]), // call_vn2
 
-            // special cases to no-op: (input/output streams & sound effects)
+            // special cases to no-op: (buffering, input/output streams & sound effects)
             // these might be present in some v3 games but aren't implemented yet
-            (VAR_243, _) | (VAR_244, _) | (VAR_245, _) => (),
+            (VAR_242, _) | (VAR_243, _) | (VAR_244, _) | (VAR_245, _) => (),
 
             _ => panic!(
                 "\n\nOpcode not yet implemented: {} ({:?}) @ {:#04x}\n\n",
@@ -1380,9 +1943,31 @@ impl Zmachine {
             "$have_prop",
             "$undo",
             "$redo",
-            "$redo",
+            "$branches",
+            "$switch",
+            "$label",
+            "$mark",
+            "$marks",
+            "$goto",
             "$teleport",
             "$steal",
+            "$break",
+            "$step",
+            "$next",
+            "$finish",
+            "$continue",
+            "$watch",
+            "$globals",
+            "$g",
+            "$set",
+            "$locals",
+            "$stack",
+            "$push",
+            "$pop",
+            "$poke",
+            "$bt",
+            "$profile",
+            "$quit",
             "$help",
         ];
 
@@ -1394,6 +1979,7 @@ impl Zmachine {
             "\
             Available debug commands: \n\n\
             $dump               (list stack frames and PC) \n\
+            $bt                 (list the routines being called, innermost first) \n\
             $dict               (show games's dictionary) \n\
             $tree               (list current object tree) \n\
             $room               (show current room's sub-tree) \n\
@@ -1406,10 +1992,31 @@ impl Zmachine {
             $props num/name     (list object properties) \n\
             $header             (show header info) \n\
             $history            (list saved states) \n\
-            $have_attr num      (list objects that have given attribute enabled) \n\
-            $have_prop num      (list objects that have given property) \n\
+            $branches           (show every branch of the undo history) \n\
+            $switch num/label   (switch to another branch of the history) \n\
+            $label text         (label the current point in the history) \n\
+            $mark name          (set a checkpoint to come back to later) \n\
+            $marks              (list checkpoints) \n\
+            $goto name          (jump to a checkpoint) \n\
+            $have_attr num/name (list objects that have given attribute enabled) \n\
+            $have_prop num/name (list objects that have given property) \n\
             $teleport num/name  (teleport to a room) \n\
             $steal num/name     (takes any item) \n\
+            $break addr/name    (stop at an instruction, routine or file.inf:line, again to remove) \n\
+            $step               (run one instruction) \n\
+            $next               (run one instruction, stepping over calls) \n\
+            $finish             (run until the current routine returns) \n\
+            $continue           (run until the next breakpoint) \n\
+            $watch what [break] (log changes to: g12, mem addr, attr num n, parent num) \n\
+            $globals            (list every global variable) \n\
+            $g num/name         (show a global variable) \n\
+            $set var value      (set g12, local0 or sp to a value) \n\
+            $locals             (list the current routine's local variables) \n\
+            $stack              (list the current routine's stack) \n\
+            $push value         (push a value onto the stack) \n\
+            $pop                (pop a value off the stack) \n\
+            $poke addr value    (set a byte of dynamic memory, addr in hex) \n\
+            $profile [off]      (start profiling, show the results so far, or stop) \n\
             $undo \n\
             $redo \n\
             $quit
@@ -1422,10 +2029,15 @@ impl Zmachine {
         let parts: Vec<_> = input.split_whitespace().collect();
         let (command, rest) = parts.split_first().unwrap();
         let arg = &rest.join(" ");
+        let depth = self.frames.len();
 
         match *command {
             "$help" => self.print_command_help(),
             "$dump" => self.debug_dump(),
+            "$bt" => {
+                let backtrace = self.backtrace();
+                self.ui.debug(&backtrace);
+            }
             "$dict" => self.debug_dictionary(),
             "$tree" => self.debug_object_tree(),
             "$room" => self.debug_room(),
@@ -1438,15 +2050,40 @@ impl Zmachine {
             "$simple" => self.debug_object_simple(arg.parse().unwrap_or(1)),
             "$header" => self.debug_header(),
             "$history" => self.debug_history(),
+            "$branches" => self.debug_branches(),
+            "$label" => self.debug_label(arg),
+            "$mark" => self.debug_mark(arg),
+            "$marks" => self.debug_marks(),
             "$have_attr" => self.debug_have_attribute(arg),
             "$have_prop" => self.debug_have_property(arg),
             "$steal" => self.debug_steal(arg),
             "$teleport" => self.debug_teleport(arg),
-            "$quit" => process::exit(0),
+            "$break" => self.debug_break(arg),
+            "$step" => should_ask_again = !self.resume(Some(Stepping::Step)),
+            "$next" => should_ask_again = !self.resume(Some(Stepping::Next(depth))),
+            "$finish" => should_ask_again = !self.resume(Some(Stepping::Finish(depth))),
+            "$continue" => should_ask_again = !self.resume(None),
+            "$watch" => self.debug_watch(arg),
+            "$globals" => self.debug_globals(),
+            "$g" => self.debug_global(arg),
+            "$set" => self.debug_set(arg),
+            "$locals" => self.debug_locals(),
+            "$stack" => self.debug_stack(),
+            "$push" => self.debug_push(arg),
+            "$pop" => self.debug_pop(),
+            "$poke" => self.debug_poke(arg),
+            "$profile" => self.debug_profile(arg),
+            "$quit" => {
+                self.write_profile();
+                self.ui.reset();
+                process::exit(0);
+            }
             // if undo/redo fails, should ask for input again
             // if they succeed, do nothing because zmachine state changed
             "$undo" => should_ask_again = !self.undo(),
             "$redo" => should_ask_again = !self.redo(),
+            "$switch" => should_ask_again = !self.switch_timeline(arg),
+            "$goto" => should_ask_again = !self.goto_checkpoint(arg),
             // unrecognized commands should ask for user input again
             _ => {
                 should_ask_again = false;
@@ -1456,82 +2093,373 @@ impl Zmachine {
         should_ask_again
     }
 
-    // Terminal UI only
-    #[allow(dead_code)]
-    pub fn run(&mut self) {
-        self.ui.clear();
+    /// Runs the game until it needs something from the outside: input, a save
+    /// or restore, or the end of the game. The paused instruction is resumed
+    /// by the matching `feed`, `feed_char`, `finish_save` or `finish_restore`.
+    /// (Calling this again before resuming just returns the same event.)
+    pub fn run_until_event(&mut self) -> Event {
+        if let Some(event) = self.paused_event() {
+            return event;
+        }
 
-        // continue instructions until the quit instruction
-        loop {
-            let instr = self.decode_instruction(self.pc);
+        let budget = self.options.instruction_budget;
+        let watchdog = self.options.watchdog_limit;
+        let mut count = 0;
 
-            if instr.opcode == Opcode::OP0_186 {
-                break;
-            }
+        // (changes from outside the game, like input or debug commands,
+        // don't count as watch hits)
+        self.refresh_watches();
 
-            self.handle_instruction(&instr);
+        // (the profile's clock only runs in here)
+        if let Some(ref mut profile) = self.profile {
+            profile.resume();
         }
 
-        self.ui.reset();
-    }
-
-    // Web UI only
-    #[allow(dead_code)]
-    pub fn step(&mut self) -> bool {
-        // loop through instructions until user input is needed
-        // (saves/restores need a save name, read instructions need user input)
-        // Pauses on these instructions and control is passed back to js
         loop {
+            if budget > 0 && count == budget {
+                self.pause_profile();
+                return Event::Running;
+            }
+
+            if watchdog > 0 && self.quiet_instrs >= watchdog {
+                self.quiet_instrs = 0;
+                self.pause_profile();
+                return Event::Stuck;
+            }
+
+            if self.skip_break {
+                self.skip_break = false;
+            } else if self.should_break() {
+                self.stepping = None;
+                self.stopped = true;
+                self.quiet_instrs = 0;
+                self.pause_profile();
+                self.debug_stopped();
+                return Event::Break;
+            }
+
             let instr = self.decode_instruction(self.pc);
+            count += 1;
+
+            if let Some(ref mut profile) = self.profile {
+                profile.instruction(instr.opcode, self.frames.len());
+            }
 
             if self.options.log_instructions {
                 write!(self.instr_log, "\n{}", &instr).unwrap();
             }
 
-            match instr.opcode {
-                // SAVE
-                Opcode::OP0_181 => {
-                    let pc = instr.next - 1;
-                    let state = self.make_save_state(pc);
-                    self.send_save_message("save", &state);
+            let event = match instr.opcode {
+                Opcode::VAR_228 => Event::LineInput,
+                Opcode::VAR_246 => Event::CharInput,
+                Opcode::OP0_181 => Event::SaveRequested,
+                Opcode::OP0_182 => Event::RestoreRequested,
+                Opcode::OP0_186 => Event::Quit,
+                _ => {
+                    self.quiet_instrs = if instr.prints() { 0 } else { self.quiet_instrs + 1 };
 
-                    // Advance the pc, assuming that the save was successful
-                    self.process_save_result(&instr);
+                    if self.watches.is_empty() {
+                        self.handle_instruction(&instr);
+                        continue;
+                    }
+
+                    self.watch_writer = Some(self.writer(&instr));
+                    self.handle_instruction(&instr);
+                    self.finish_writes();
+                    continue;
                 }
-                // RESTORE (breaks loop)
-                Opcode::OP0_182 => {
-                    self.ui.message("restore", "");
-                    self.paused_instr = Some(instr);
+            };
+
+            self.paused_instr = Some(instr);
+            self.quiet_instrs = 0;
+
+            if let Some(ref mut profile) = self.profile {
+                profile.pause();
+
+                if event == Event::LineInput || event == Event::CharInput {
+                    profile.turn();
+                }
+            }
+
+            if event == Event::LineInput {
+                self.record_turn();
+            }
+
+            if event == Event::Quit {
+                self.write_profile();
+            }
+
+            return event;
+        }
+    }
+
+    // Every front end saves the turn here, just BEFORE reading input
+    // (a new turn only starts if there was real input since the last read,
+    // debug commands just refresh the current state)
+    fn record_turn(&mut self) {
+        // need to update the status bar before each read
+        self.update_status_bar();
+        self.update_screen_size();
+
+        let state = self.make_history_state(self.pc);
+        let (location, _) = self.get_status();
+
+        match self.pending_input.take() {
+            Some(input) => self.history.push(&input, location, state),
+            None => self.history.replace_current(location, state),
+        }
+    }
+
+    /// What the machine is currently stopped for, if anything
+    pub fn paused_event(&self) -> Option<Event> {
+        if self.stopped {
+            return Some(Event::Break);
+        }
+
+        let instr = self.paused_instr.as_ref()?;
+
+        match instr.opcode {
+            Opcode::VAR_228 => Some(Event::LineInput),
+            Opcode::VAR_246 => Some(Event::CharInput),
+            Opcode::OP0_181 => Some(Event::SaveRequested),
+            Opcode::OP0_182 => Some(Event::RestoreRequested),
+            Opcode::OP0_186 => Some(Event::Quit),
+            _ => None,
+        }
+    }
+
+    // (a front end answering the wrong event gets told so, instead of
+    // bringing the whole machine down)
+    fn take_paused(&mut self, event: Event) -> Option<Instruction> {
+        match self.paused_event() {
+            Some(ref paused) if *paused == event => self.paused_instr.take(),
+            paused => {
+                let msg = format!("[Not waiting for {:?} (paused for {:?}), ignored]", event, paused);
+                self.ui.debug(&msg);
+                None
+            }
+        }
+    }
+
+    /// Answers an `Event::LineInput`. Debug commands (`$undo`, `$tree`...)
+    /// are handled here too, and leave the machine waiting for input again
+    /// unless they changed the state. Anything else is ignored (with a
+    /// debug message) if the machine isn't waiting for a line.
+    pub fn feed(&mut self, input: &str) {
+        // these inputs shouldn't be processed normally
+        if self.is_debug_command(input) {
+            let stopped = self.stopped;
+            let ask_again = self.handle_debug_command(input);
+
+            // at a breakpoint the front end asks for the next command itself
+            // (unless the command went back to the last prompt, like $undo)
+            if stopped && (self.stopped || self.skip_break) {
+                return;
+            }
+
+            // (only a line input can stand in for the turn's state)
+            if ask_again && self.paused_event() == Some(Event::LineInput) {
+                self.record_turn();
+            }
+
+            // (the game won't print another prompt on its own)
+            self.ui.print("\n>");
+            return;
+        }
+
+        if self.stopped {
+            self.ui.debug("[Stopped in the debugger: $step, $next, $finish or $continue to go on]");
+            return;
+        }
+
+        let instr = match self.take_paused(Event::LineInput) {
+            Some(instr) => instr,
+            None => return,
+        };
+
+        // the next read will save a new state to the history for this input
+        self.pending_input = Some(String::from(input));
+
+        let args = self.get_arguments(instr.operands.as_slice());
+
+        // (the input goes in as if the read instruction wrote it)
+        self.watch_writer = Some(self.writer(&instr));
+        self.do_sread_second(args[0], args[1], String::from(input));
+
+        // v5+ stores the key that ended the input
+        if let Some(index) = instr.store {
+            self.write_variable(index, 13);
+        }
+
+        self.finish_writes();
+        self.pc = instr.next;
+    }
+
+    /// Answers an `Event::CharInput` with a zscii key code
+    pub fn feed_char(&mut self, key: u16) {
+        let instr = match self.take_paused(Event::CharInput) {
+            Some(instr) => instr,
+            None => return,
+        };
+
+        // (the arguments might need to come off the stack)
+        self.get_arguments(instr.operands.as_slice());
 
-                    return false;
+        self.watch_writer = Some(self.writer(&instr));
+        self.process_result(&instr, key);
+        self.finish_writes();
+    }
+
+    /// The save file for an `Event::SaveRequested` (Quetzal format)
+    pub fn save_data(&self) -> Vec<u8> {
+        match self.paused_instr {
+            // The save PC points to either the save instructions branch data or
+            // store data. In either case, this is the last byte of the
+            // instruction. (so -1)
+            Some(ref instr) if instr.opcode == Opcode::OP0_181 => {
+                self.make_save_state(instr.next - 1)
+            }
+            _ => panic!("Can't make a save, the game didn't ask for one"),
+        }
+    }
+
+    /// Answers an `Event::SaveRequested`, telling the game if the save worked
+    pub fn finish_save(&mut self, saved: bool) {
+        let instr = match self.take_paused(Event::SaveRequested) {
+            Some(instr) => instr,
+            None => return,
+        };
+
+        if saved {
+            self.process_save_result(&instr);
+        } else {
+            self.process_result(&instr, 0);
+        }
+    }
+
+    /// Answers an `Event::RestoreRequested` with a save from `save_data` (or
+    /// None to cancel)
+    pub fn finish_restore(&mut self, data: Option<&[u8]>) {
+        let instr = match self.take_paused(Event::RestoreRequested) {
+            Some(instr) => instr,
+            None => return,
+        };
+
+        // (a save that can't be restored fails like a cancelled one)
+        match data.map(|data| self.restore_state(data)) {
+            Some(Ok(())) => self.process_restore_result(),
+            Some(Err(err)) => {
+                self.ui.print(&format!("\n[{}]\n", err));
+                self.process_result(&instr, 0);
+            }
+            None => self.process_result(&instr, 0),
+        }
+    }
+
+    /// The whole machine state right now, as a Quetzal save that picks up
+    /// where it left off (ex: at a read instruction, waiting for input)
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.make_save_state(self.pc)
+    }
+
+    /// Goes back to a state from `snapshot` (or says why it can't, leaving
+    /// the machine as it was)
+    pub fn restore_snapshot(&mut self, data: &[u8]) -> Result<(), String> {
+        self.restore_state(data)
+    }
+
+    // Terminal UI only
+    #[allow(dead_code)]
+    pub fn run(&mut self) {
+        self.ui.clear();
+
+        loop {
+            match self.run_until_event() {
+                Event::LineInput => {
+                    // add extra space so it doesn't look janky (non-spec)
+                    self.ui.print(" ");
+
+                    let input = self.ui.get_user_input();
+                    self.feed(&input);
                 }
-                // QUIT (breaks loop)
-                Opcode::OP0_186 => {
-                    // undo 2x - get to the savestate right before the
-                    // "are you sure?" dialog box that usually shows up:
-                    self.undos.pop();
-
-                    if let Some((_, state)) = self.undos.pop() {
-                        self.send_save_message("savestate", &state);
+                Event::CharInput => {
+                    let key = self.ui.get_user_char();
+                    self.feed_char(key);
+                }
+                Event::SaveRequested => self.do_save(),
+                Event::RestoreRequested => self.do_restore(),
+                Event::Quit => break,
+                Event::Running => (),
+                Event::Stuck => {
+                    if self.keep_waiting() {
+                        continue;
+                    }
+
+                    // back at the last prompt (or nothing to go back to)
+                    if !self.undo() {
+                        break;
                     }
 
-                    return true; // done == true
+                    self.ui.print("\n>");
+                }
+                Event::Break => {
+                    self.ui.print("\n(break) ");
+
+                    let input = self.ui.get_user_input();
+                    self.feed(&input);
                 }
-                // READ (breaks loop)
-                Opcode::VAR_228 => {
-                    let state = self.make_save_state(self.pc);
-                    self.send_save_message("savestate", &state);
+            }
+        }
+
+        self.ui.reset();
+    }
 
-                    // web ui saves current state here BEFORE processing user input
-                    let (location, _) = self.get_status();
-                    self.current_state = Some((location, state));
-                    self.paused_instr = Some(instr);
+    // Terminal UI only - asks what to do about a game that looks stuck
+    fn keep_waiting(&mut self) -> bool {
+        self.ui.print(
+            "\n[The game has been running for a while without printing anything, \
+             it might be stuck in a loop. Keep waiting? (y/n, no undoes the last \
+             command)]\n> ",
+        );
+
+        let answer = self.ui.get_user_input();
+        !answer.trim().to_lowercase().starts_with('n')
+    }
+
+    // Web UI / JSON mode
+    #[allow(dead_code)]
+    pub fn step(&mut self) -> Event {
+        // run until user input is needed
+        // (restores need a save file, read instructions need user input)
+        // Pauses on these instructions and control is passed back to js,
+        // same as when the instruction budget runs out or the game is stuck
+        loop {
+            match self.run_until_event() {
+                Event::SaveRequested => {
+                    let state = self.save_data();
+                    self.send_save_message("save", &state);
 
-                    return false;
+                    // assumes that the save was successful
+                    self.finish_save(true);
                 }
-                _ => {
-                    self.handle_instruction(&instr);
+                Event::RestoreRequested => {
+                    self.ui.message("restore", "");
+                    return Event::RestoreRequested;
+                }
+                Event::Quit => {
+                    // get to the savestate right before the "are you sure?"
+                    // dialog box that usually shows up:
+                    let parent = self.history.parent().map(|node| node.id);
+
+                    if let Some(id) = parent {
+                        let state = self.history.materialize(id);
+                        let data = self.export_history_state(&state);
+                        self.send_save_message("savestate", &data);
+                    }
+
+                    return Event::Quit;
                 }
+                event => return event,
             }
         }
     }
@@ -1540,56 +2468,70 @@ impl Zmachine {
     // (passes control back JS afterwards)
     #[allow(dead_code)]
     pub fn handle_input(&mut self, input: String) {
-        let instr = self.paused_instr.take().expect(
-            "Can't handle input, no paused instruction to resume",
-        );
-
-        // handle special debugging commands
-        // these inputs shouldn't be processed normally
-        if self.is_debug_command(&input) {
-            if self.handle_debug_command(&input) {
-                self.ui.print("\n>");
-            }
-
-            // return execution to JS, which will read user input again:
+        // single keys just take the first character (or return)
+        if self.paused_event() == Some(Event::CharInput) {
+            let key = input.chars().next().map_or(13, |c| c as u16);
+            self.feed_char(key);
             return;
         }
 
-        // new input changes timelines, so remove any obsolete redos
-        self.redos.clear();
+        self.feed(&input);
+    }
 
-        // move current state into the undo list now
-        if self.current_state.is_some() {
-            self.undos.push(self.current_state.take().unwrap());
+    // Web UI only
+    // (an empty string cancels the restore, bad data leaves it waiting)
+    #[allow(dead_code)]
+    pub fn restore(&mut self, data: &str) -> Result<(), String> {
+        if data.is_empty() {
+            self.finish_restore(None);
+            return Ok(());
         }
 
-        // explicitly handle read (need to get args first)
-        let args = self.get_arguments(instr.operands.as_slice());
-        self.do_sread_second(args[0], args[1], input);
-        self.pc = instr.next;
+        let state = base64::decode(data).map_err(|err| format!("Invalid data: {}", err))?;
+        self.read_save(&state)?;
+
+        self.finish_restore(Some(&state));
+        Ok(())
     }
 
     // Web UI only
+    // Loads a saved state _without_ processing a restore result (like the above)
     #[allow(dead_code)]
-    pub fn restore(&mut self, data: &str) {
-        let state = base64::decode(&data);
+    pub fn load_savestate(&mut self, data: &str) -> Result<(), String> {
+        let state = base64::decode(data).map_err(|err| format!("Invalid data: {}", err))?;
+        self.restore_state(&state)
+    }
 
-        // cancel restore (sending an empty string or if base64 decode fails)
-        if data.is_empty() || state.is_err() {
-            let instr = self.paused_instr.take().unwrap();
-            self.process_result(&instr, 0);
-        } else {
-            self.restore_state(state.unwrap().as_slice());
-            self.process_restore_result();
+    // Web UI only
+    // Sends the current state as a save (only built when asked for, since it
+    // would be wasteful to build and encode one for every single turn)
+    #[allow(dead_code)]
+    pub fn send_savestate(&mut self) {
+        if let Some(data) = self.get_savestate() {
+            self.send_save_message("savestate", &data);
         }
     }
 
-    // Web UI only
-    // Loads a saved state _without_ processing a restore result (like the above)
+    // Web UI / JSON mode - the current turn as a save file (restored with
+    // load_savestate, it picks up at the read instruction)
     #[allow(dead_code)]
-    pub fn load_savestate(&mut self, data: &str) {
-        let state = base64::decode(data).unwrap();
-        self.restore_state(state.as_slice());
+    pub fn get_savestate(&mut self) -> Option<Vec<u8>> {
+        let current = self.history.current().map(|node| node.id)?;
+        let state = self.history.materialize(current);
+
+        Some(self.export_history_state(&state))
+    }
+
+    // Web UI / JSON mode - what the paused instruction is waiting for
+    #[allow(dead_code)]
+    pub fn waiting_for(&self) -> Option<&'static str> {
+        match self.paused_event() {
+            Some(Event::LineInput) => Some("line"),
+            Some(Event::CharInput) => Some("char"),
+            Some(Event::RestoreRequested) => Some("restore"),
+            Some(Event::Break) => Some("debug"),
+            _ => None,
+        }
     }
 
     // Web UI only
@@ -1799,7 +2741,7 @@ impl Zmachine {
     // OP1_138
     fn do_print_obj(&mut self, obj: u16) {
         let name = self.get_object_name(obj);
-        self.ui.print_object(&name);
+        self.ui.print_object(obj, &name);
     }
 
     // OP1_139
@@ -1859,8 +2801,8 @@ impl Zmachine {
 
     // OP0_180 : nop, never actually used
 
-    // OP0_181
-    fn do_save(&mut self, instr: &Instruction) {
+    // OP0_181 (terminal ui, the web ui sends the save to js instead)
+    fn do_save(&mut self) {
         let prompt = format!("\nFilename [{}]: ", self.save_name);
         self.ui.print(&prompt);
 
@@ -1871,7 +2813,7 @@ impl Zmachine {
         match input.to_lowercase().as_ref() {
             "" | "yes" | "y" => path.push(&self.save_name),
             "no" | "n" | "cancel" => {
-                self.process_result(instr, 0);
+                self.finish_save(false);
                 return;
             }
             _ => path.push(input),
@@ -1881,20 +2823,18 @@ impl Zmachine {
             file = handle;
         } else {
             self.ui.print("Can't save to that file, try another?\n");
-            self.process_result(instr, 0);
+            self.finish_save(false);
             return;
         }
 
         // save file name for next use
         self.save_name = path.file_name().unwrap().to_string_lossy().into_owned();
 
-        // The save PC points to either the save instructions branch data or store
-        // data. In either case, this is the last byte of the instruction. (so -1)
-        let pc = instr.next - 1;
-        let data = self.make_save_state(pc);
+        let data = self.save_data();
         file.write_all(&data[..]).expect("Error saving to file");
+        self.save_history(&path);
 
-        self.process_save_result(instr);
+        self.finish_save(true);
     }
 
     fn process_save_result(&mut self, instr: &Instruction) {
@@ -1903,8 +2843,8 @@ impl Zmachine {
         self.process_result(instr, 1);
     }
 
-    // OP0_182
-    fn do_restore(&mut self, instr: &Instruction) {
+    // OP0_182 (terminal ui)
+    fn do_restore(&mut self) {
         let prompt = format!("\nFilename [{}]: ", self.save_name);
         self.ui.print(&prompt);
 
@@ -1916,7 +2856,7 @@ impl Zmachine {
         match input.to_lowercase().as_ref() {
             "" | "yes" | "y" => path.push(&self.save_name),
             "no" | "n" | "cancel" => {
-                self.process_result(instr, 0);
+                self.finish_restore(None);
                 return;
             }
             _ => path.push(input),
@@ -1926,7 +2866,7 @@ impl Zmachine {
             file = handle;
         } else {
             self.ui.print("Can't open that file, try another?\n");
-            self.process_result(instr, 0);
+            self.finish_restore(None);
             return;
         }
 
@@ -1937,8 +2877,8 @@ impl Zmachine {
         file.read_to_end(&mut data).expect(
             "Error reading save file",
         );
-        self.restore_state(data.as_slice());
-        self.process_restore_result();
+        self.finish_restore(Some(data.as_slice()));
+        self.load_history(&path);
     }
 
     fn process_restore_result(&mut self) {
@@ -1971,6 +2911,7 @@ impl Zmachine {
         self.frames.clear();
         self.frames.push(Frame::empty());
         self.memory.write(0, self.original_dynamic.as_slice());
+        self.object_changes = None;
     }
 
     // OP0_184
@@ -1990,7 +2931,7 @@ impl Zmachine {
     }
 
     // OP0_188
-    fn do_show_status(&self) {
+    fn do_show_status(&mut self) {
         self.update_status_bar();
     }
 
@@ -2028,10 +2969,19 @@ impl Zmachine {
         }
 
         let first_instr = read.position();
-        let frame = Frame::new(instr.next, instr.store, locals, args);
+        let frame = Frame::new(routine_addr, addr, instr.next, instr.store, locals, args);
 
         self.pc = first_instr;
         self.frames.push(frame);
+
+        if let Some(ref mut profile) = self.profile {
+            profile.called(routine_addr, self.frames.len());
+        }
+
+        // routine breakpoints stop at the first instruction
+        if self.breakpoints.contains(&routine_addr) {
+            self.stepping = Some(Stepping::Step);
+        }
     }
 
     // VAR_225
@@ -2040,6 +2990,8 @@ impl Zmachine {
         let word_addr = array_addr.wrapping_add(word_index);
 
         self.memory.write_word(word_addr as usize, value);
+        self.memory_written(word_addr as usize, 2);
+        self.check_watches(word_addr as usize, 2);
     }
 
     // VAR_226
@@ -2047,6 +2999,8 @@ impl Zmachine {
         let word_addr = array.wrapping_add(index);
 
         self.memory.write_byte(word_addr as usize, value as u8);
+        self.memory_written(word_addr as usize, 1);
+        self.check_watches(word_addr as usize, 1);
     }
 
     // VAR_227
@@ -2054,43 +3008,7 @@ impl Zmachine {
         self.put_prop(obj, prop, value);
     }
 
-    // VAR_228
-    fn do_sread(&mut self, instr: &Instruction, text_addr: u16, parse_addr: u16) {
-        // need to update the status bar before each read
-        self.update_status_bar();
-        // add extra space so it doesn't look janky (non-spec)
-        self.ui.print(" ");
-
-        let input = self.ui.get_user_input();
-
-        // handle special debugging commands
-        // these inputs shouldn't be processed normally
-        if self.is_debug_command(&input) {
-            if self.handle_debug_command(&input) {
-                self.ui.print("\n>");
-                self.do_sread(instr, text_addr, parse_addr);
-            }
-
-            return;
-        }
-
-        self.do_sread_second(text_addr, parse_addr, input);
-
-        // save state JUST after having processed user input
-        // new input changes timelines, so remove any obsolete redos
-        self.redos.clear();
-
-        // push the current state into the undo list
-        if self.current_state.is_some() {
-            self.undos.push(self.current_state.take().unwrap());
-        }
-
-        // and save the current state
-        let location = self.get_object_name(self.read_global(0));
-        let state = self.make_save_state(instr.next);
-        self.current_state = Some((location, state));
-    }
-
+    // VAR_228 (see feed)
     fn do_sread_second(&mut self, text_addr: u16, parse_addr: u16, mut raw: String) {
         let text_addr = text_addr as usize;
         let parse_addr = parse_addr as usize;
@@ -2117,12 +3035,23 @@ impl Zmachine {
             self.memory.write(text_addr + 2, bytes);
         }
 
+        self.check_watches(text_addr + 1, len + 1);
+
         // skip tokenization step if parse_addr is 0
         if parse_addr != 0 {
             self.tokenise(input, parse_addr);
         }
     }
 
+    // VAR_240
+    fn do_get_cursor(&mut self, array: u16) {
+        let (line, column) = self.ui.get_cursor();
+
+        self.memory.write_word(array as usize, line);
+        self.memory.write_word(array as usize + 2, column);
+        self.check_watches(array as usize, 4);
+    }
+
     // VAR_229
     fn do_print_char(&mut self, chr: u16) {
         self.ui.print(&(chr as u8 as char).to_string());
@@ -2246,100 +3175,806 @@ impl Zmachine {
         self.ui.debug(&out);
     }
 
-    fn debug_dump(&mut self) {
-        let mut out = String::new();
-        writeln!(out, "PC @ {}", self.pc).unwrap();
+    fn should_break(&self) -> bool {
+        let depth = self.frames.len();
 
-        for frame in &self.frames {
-            writeln!(out, "{}", frame).unwrap();
+        self.breakpoints.contains(&self.pc) || match self.stepping {
+            Some(Stepping::Step) => true,
+            Some(Stepping::Next(from)) => depth <= from,
+            Some(Stepping::Finish(from)) => depth < from,
+            None => false,
         }
-
-        self.ui.debug(&out);
     }
 
-    fn debug_object_simple(&mut self, obj_num: u16) {
-        self.ui.debug(&format!("\nObject #{}", obj_num));
+    // where the debugger stopped: the instruction about to run, the current
+    // frame, and the next few instructions (up to anything that jumps away)
+    fn debug_stopped(&mut self) {
+        let mut instr = self.decode_instruction(self.pc);
+        let mut out = String::new();
 
-        let addr = self.get_object_addr(obj_num);
-        let mut read = self.memory.get_reader(addr);
+        let line = self
+            .debug_info
+            .source_line(self.pc)
+            .map_or(String::new(), |line| format!(", {}", line));
 
-        if self.version <= 3 {
-            self.ui.debug(&format!(
-                "Attributes: {:08b} {:08b} {:08b} {:08b}",
-                read.byte(),
-                read.byte(),
-                read.byte(),
-                read.byte()
-            ));
+        match self.debug_info.routine_name(self.frames.last().unwrap().routine) {
+            Some(name) => writeln!(out, "\n[Stopped at {:x} in {}{}]", self.pc, name, line),
+            None => writeln!(out, "\n[Stopped at {:x}{}]", self.pc, line),
+        }.unwrap();
+        writeln!(out, "> {}", instr).unwrap();
+        writeln!(out, "  {}", self.frames.last().unwrap()).unwrap();
 
-            self.ui.debug(&format!(
-                "Parent: {}, Sibling: {}, Child: {}",
-                read.byte(),
-                read.byte(),
-                read.byte()
-            ));
-        } else {
-            self.ui.debug(&format!(
-                "Attributes: {:08b} {:08b} {:08b} {:08b} {:08b} {:08b}",
-                read.byte(),
-                read.byte(),
-                read.byte(),
-                read.byte(),
-                read.byte(),
-                read.byte()
-            ));
+        for _ in 0..4 {
+            if !instr.advances() {
+                break;
+            }
 
-            self.ui.debug(&format!(
-                "Parent: {}, Sibling: {}, Child: {}",
-                read.word(),
-                read.word(),
-                read.word()
-            ));
+            instr = self.decode_instruction(instr.next);
+            writeln!(out, "  {}", instr).unwrap();
         }
 
-        let prop_addr = read.word() as usize;
-        self.ui.debug(&format!("Property table @ {:x}", prop_addr));
+        self.ui.debug(&out);
+    }
 
-        let text_length = self.memory.read_byte(prop_addr);
-        let short_name = if text_length > 0 {
-            self.read_zstring(prop_addr + 1)
-        } else {
-            String::new()
-        };
+    // with no address, lists the breakpoints. routines and source lines from
+    // the debug file can be given by name (or file:line)
+    fn debug_break(&mut self, arg: &str) {
+        if arg.is_empty() {
+            let list = self
+                .breakpoints
+                .iter()
+                .map(|addr| self.address_label(*addr))
+                .collect::<Vec<_>>();
 
-        self.ui.debug(&format!("{} (len: {})\n", short_name, text_length));
-    }
+            self.ui.debug(&format!("Breakpoints: [{}]", list.join(", ")));
+            return;
+        }
 
-    fn get_object_number(&self, input: &str) -> u16 {
-        if let Ok(num) = input.parse() {
-            num
-        } else if let Some(num) = self.find_object(input) {
-            num
+        let addr = match self.debug_info.routine_addr(arg).or_else(|| self.debug_info.line_addr(arg)) {
+            Some(addr) => addr,
+            None => match usize::from_str_radix(arg.trim_start_matches("0x"), 16) {
+                Ok(addr) if addr < self.memory.len() => addr,
+                _ => {
+                    self.ui.debug(&format!("Not a routine or an address (in hex): {}", arg));
+                    return;
+                }
+            },
+        };
+
+        if self.breakpoints.remove(&addr) {
+            self.ui.debug(&format!("Removed breakpoint at {}", self.address_label(addr)));
         } else {
-            0
+            self.breakpoints.insert(addr);
+            self.ui.debug(&format!("Breakpoint at {}", self.address_label(addr)));
         }
     }
 
-    fn debug_object(&mut self, input: &str) {
-        let num = self.get_object_number(input);
-        if num == 0 {
-            return;
+    // "1a2b", or "1a2b (Initialise)" for the start of a named routine, with
+    // the source line if the debug file has it: "1a2b (Initialise, game.inf:12)"
+    fn address_label(&self, addr: usize) -> String {
+        match (self.debug_info.routine_name(addr), self.debug_info.source_line(addr)) {
+            (Some(name), Some(line)) => format!("{:x} ({}, {})", addr, name, line),
+            (Some(name), None) => format!("{:x} ({})", addr, name),
+            (None, Some(line)) => format!("{:x} ({})", addr, line),
+            (None, None) => format!("{:x}", addr),
         }
-
-        let mut obj = Object::new(num, self);
-        self.add_object_children(&mut obj);
-        self.ui.debug(&obj.to_string());
     }
 
-    fn debug_object_tree(&mut self) {
-        let tree = self.get_object_tree();
-        self.ui.debug(&tree.to_string());
-    }
+    // $step/$next/$finish/$continue, at a breakpoint these pick up where it
+    // stopped (returns true), otherwise they apply after the next input
+    fn resume(&mut self, stepping: Option<Stepping>) -> bool {
+        self.stepping = stepping;
 
-    fn debug_object_properties(&mut self, input: &str) {
-        let num = self.get_object_number(input);
-        if num == 0 {
-            return;
+        if !self.stopped {
+            if stepping.is_some() {
+                self.ui.debug("[Will stop after the next command]");
+            }
+            return false;
+        }
+
+        self.stopped = false;
+        self.skip_break = true;
+        true
+    }
+
+    // with nothing to watch, lists the watches
+    fn debug_watch(&mut self, arg: &str) {
+        if arg.is_empty() {
+            let list = self
+                .watches
+                .iter()
+                .map(|point| {
+                    let stop = if point.stop { " (break)" } else { "" };
+                    format!("{}{}", self.watch_label(point.watch), stop)
+                })
+                .collect::<Vec<_>>();
+
+            self.ui.debug(&format!("Watching: [{}]", list.join(", ")));
+            return;
+        }
+
+        let stop = arg.ends_with(" break");
+        let arg = arg.trim_end_matches(" break");
+
+        let watch = match self.parse_watch(arg) {
+            Ok(watch) => watch,
+            Err(err) => {
+                self.ui.debug(&err);
+                return;
+            }
+        };
+
+        if let Some(index) = self.watches.iter().position(|point| point.watch == watch) {
+            self.watches.remove(index);
+            self.ui.debug(&format!("Stopped watching {}", self.watch_label(watch)));
+            return;
+        }
+
+        let value = self.watch_value(watch);
+        self.watches.push(Watchpoint { watch, value, stop });
+        let msg = format!("Watching {} (now {})", self.watch_label(watch), self.show_watch_value(watch, value));
+        self.ui.debug(&msg);
+    }
+
+    // "g12", "mem 1a2b" (hex), "attr <obj> <n>", "parent <obj>" (objects by
+    // number or name, globals and attributes by name too with a debug file)
+    fn parse_watch(&self, arg: &str) -> Result<Watch, String> {
+        let mut words = arg.splitn(2, ' ');
+        let kind = words.next().unwrap_or("");
+        let rest = words.next().unwrap_or("").trim();
+
+        let object = |input: &str| match self.get_object_number(input) {
+            num if num > 0 && num <= self.get_total_object_count() => Ok(num),
+            _ => Err(format!("Can't find object: {}", input)),
+        };
+
+        match kind {
+            "attr" => {
+                let (obj, attr) = match rest.rfind(' ') {
+                    Some(index) => (&rest[..index], &rest[index + 1..]),
+                    None => return Err(String::from("Usage: $watch attr <obj> <n>")),
+                };
+
+                match self.parse_attribute(attr) {
+                    Some(num) => Ok(Watch::Attr(object(obj)?, num)),
+                    None => Err(format!("Not an attribute: {}", attr)),
+                }
+            }
+            "parent" => Ok(Watch::Parent(object(rest)?)),
+            "mem" => match usize::from_str_radix(rest.trim_start_matches("0x"), 16) {
+                Ok(addr) if addr + 1 < self.memory.len() => Ok(Watch::Memory(addr)),
+                _ => Err(format!("Not an address (in hex): {}", rest)),
+            },
+            _ if rest.is_empty() => match self.debug_info.global_index(kind) {
+                Some(index) => Ok(Watch::Global(index)),
+                None => match kind.strip_prefix('g').map(str::parse::<u8>) {
+                    Some(Ok(index)) if index < 240 => Ok(Watch::Global(index)),
+                    _ => Err(format!("Not a global: {}", kind)),
+                },
+            },
+            _ => Err(format!("Can't watch: {}", arg)),
+        }
+    }
+
+    // like the Display for watches, with names from the debug file
+    fn watch_label(&self, watch: Watch) -> String {
+        match watch {
+            Watch::Global(index) => self.variable_label(index + 16, 0),
+            Watch::Attr(obj, attr) => format!("attr {} {}", obj, self.attribute_label(attr)),
+            _ => watch.to_string(),
+        }
+    }
+
+    fn watch_value(&self, watch: Watch) -> u16 {
+        match watch {
+            Watch::Global(index) => self.read_global(index),
+            Watch::Memory(addr) => self.memory.read_word(addr),
+            Watch::Attr(obj, attr) => self.test_attr(obj, attr),
+            Watch::Parent(obj) => self.get_parent(obj),
+        }
+    }
+
+    fn show_watch_value(&self, watch: Watch, value: u16) -> String {
+        match watch {
+            Watch::Global(_) | Watch::Memory(_) => format!("{:04x}", value),
+            Watch::Attr(..) => format!("{}", value),
+            Watch::Parent(_) => format!("{} ({})", value, self.get_object_name(value)),
+        }
+    }
+
+    fn refresh_watches(&mut self) {
+        for index in 0..self.watches.len() {
+            self.watches[index].value = self.watch_value(self.watches[index].watch);
+        }
+    }
+
+    // the bytes each watch covers
+    fn watch_range(&self, watch: Watch) -> (usize, usize) {
+        match watch {
+            Watch::Global(index) => (self.globals_addr + index as usize * 2, 2),
+            Watch::Memory(addr) => (addr, 2),
+            Watch::Attr(obj, attr) => (self.get_object_addr(obj) + attr as usize / 8, 1),
+            Watch::Parent(obj) => {
+                let addr = self.get_object_addr(obj) + self.attr_width;
+                (addr, if self.version <= 3 { 1 } else { 2 })
+            }
+        }
+    }
+
+    // the instruction and routine (None for main) that watch hits blame
+    fn writer(&self, instr: &Instruction) -> (usize, Option<usize>) {
+        let routine = match self.frames.len() {
+            1 => None,
+            _ => Some(self.frames.last().unwrap().routine),
+        };
+
+        (instr.addr, routine)
+    }
+
+    // a watch that breaks stops before the next instruction
+    fn finish_writes(&mut self) {
+        self.watch_writer = None;
+
+        if self.watch_break {
+            self.watch_break = false;
+            self.stepping = Some(Stepping::Step);
+        }
+    }
+
+    // called by the writes a game can make (write_global, storew, storeb,
+    // put_prop, the object tree, read and tokenise...) to compare the watches
+    // they touch with their last values. (only while an instruction runs,
+    // including a read taking its input, changes from debug commands don't
+    // count)
+    fn check_watches(&mut self, addr: usize, len: usize) {
+        let (pc, routine) = match self.watch_writer {
+            Some(writer) => writer,
+            None => return,
+        };
+
+        for index in 0..self.watches.len() {
+            let point = self.watches[index];
+            let (start, size) = self.watch_range(point.watch);
+
+            if addr >= start + size || start >= addr + len {
+                continue;
+            }
+
+            let value = self.watch_value(point.watch);
+
+            if value == point.value {
+                continue;
+            }
+
+            let routine = match routine {
+                None => String::from("main"),
+                Some(0) => String::from("?"),
+                Some(addr) => match self.debug_info.routine_name(addr) {
+                    Some(name) => name.to_string(),
+                    None => format!("{:x}", addr),
+                },
+            };
+
+            let msg = format!(
+                "[{}: {} -> {} at {:x} (routine {})]\n",
+                self.watch_label(point.watch),
+                self.show_watch_value(point.watch, point.value),
+                self.show_watch_value(point.watch, value),
+                pc,
+                routine
+            );
+
+            self.ui.debug(&msg);
+            self.watches[index].value = value;
+            self.watch_break |= point.stop;
+        }
+    }
+
+    // values are decimal (negative ones too) or hex with 0x
+    fn parse_value(input: &str) -> Option<u16> {
+        if let Some(hex) = input.strip_prefix("0x") {
+            u16::from_str_radix(hex, 16).ok()
+        } else {
+            input
+                .parse::<u16>()
+                .ok()
+                .or_else(|| input.parse::<i16>().ok().map(|value| value as u16))
+        }
+    }
+
+    // the variable number for "sp", "local3" or "g12" (or a local or global
+    // by name, with a debug file)
+    fn parse_variable(&self, name: &str) -> Result<u8, String> {
+        let frame = self.frames.last().unwrap();
+        let locals = frame.locals().len();
+
+        if name == "sp" {
+            Ok(0)
+        } else if let Some(index) = self.debug_info.local_index(frame.routine, name) {
+            Ok(index as u8 + 1)
+        } else if let Some(index) = self.debug_info.global_index(name) {
+            Ok(index + 16)
+        } else if let Some(number) = name.strip_prefix("local") {
+            match number.parse::<usize>() {
+                Ok(index) if index < locals => Ok(index as u8 + 1),
+                _ => Err(format!("Not a local (this routine has {}): {}", locals, name)),
+            }
+        } else {
+            match name.trim_start_matches('g').parse::<usize>() {
+                Ok(index) if index < 240 => Ok(index as u8 + 16),
+                _ => Err(format!("Not a global: {}", name)),
+            }
+        }
+    }
+
+    // (one per line with their names, if the debug file has them)
+    fn debug_globals(&mut self) {
+        let mut out = String::new();
+
+        if self.debug_info.has_globals() {
+            for index in 0..240 {
+                let name = self.debug_info.global_name(index).unwrap_or("");
+                let line = format!("g{:<3} {:04x}  {}", index, self.read_global(index), name);
+                writeln!(out, "{}", line.trim_end()).unwrap();
+            }
+
+            self.ui.debug(&out);
+            return;
+        }
+
+        for index in 0..240 {
+            let sep = if index % 8 == 7 { "\n" } else { "  " };
+            write!(out, "g{:<3} {:04x}{}", index, self.read_global(index), sep).unwrap();
+        }
+
+        self.ui.debug(&out);
+    }
+
+    fn debug_global(&mut self, arg: &str) {
+        match self.parse_variable(arg) {
+            Ok(index) if index >= 16 => {
+                let value = self.read_global(index - 16);
+                let label = self.variable_label(index, 0);
+                self.ui.debug(&format!("{} = {:04x} ({})", label, value, value as i16));
+            }
+            Ok(_) => self.ui.debug(&format!("Not a global: {}", arg)),
+            Err(err) => self.ui.debug(&err),
+        }
+    }
+
+    // sp pushes the value
+    fn debug_set(&mut self, arg: &str) {
+        let parts: Vec<_> = arg.split_whitespace().collect();
+
+        if parts.len() != 2 {
+            self.ui.debug("Usage: $set <g12|local0|sp> <value>");
+            return;
+        }
+
+        let value = match Zmachine::parse_value(parts[1]) {
+            Some(value) => value,
+            None => {
+                self.ui.debug(&format!("Not a value: {}", parts[1]));
+                return;
+            }
+        };
+
+        match self.parse_variable(parts[0]) {
+            Ok(index) => {
+                self.write_variable(index, value);
+                self.ui.debug(&format!("{} = {:04x}", parts[0], value));
+            }
+            Err(err) => self.ui.debug(&err),
+        }
+    }
+
+    fn debug_locals(&mut self) {
+        let frame = self.frames.last().unwrap();
+        let out = frame
+            .locals()
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                let label = self.variable_label(index as u8 + 1, frame.routine);
+                format!("{} = {:04x}", label, value)
+            })
+            .collect::<Vec<_>>();
+
+        if out.is_empty() {
+            self.ui.debug("No locals");
+        } else {
+            self.ui.debug(&out.join("\n"));
+        }
+    }
+
+    fn debug_stack(&mut self) {
+        let values = self
+            .frames
+            .last()
+            .unwrap()
+            .stack()
+            .iter()
+            .map(|value| format!("{:04x}", value))
+            .collect::<Vec<_>>();
+
+        self.ui.debug(&format!("Stack: [{}]", values.join(", ")));
+    }
+
+    fn debug_push(&mut self, arg: &str) {
+        match Zmachine::parse_value(arg) {
+            Some(value) => {
+                self.stack_push(value);
+                self.debug_stack();
+            }
+            None => self.ui.debug(&format!("Not a value: {}", arg)),
+        }
+    }
+
+    fn debug_pop(&mut self) {
+        if self.frames.last().unwrap().stack().is_empty() {
+            self.ui.debug("The stack is empty");
+            return;
+        }
+
+        let value = self.stack_pop();
+        self.ui.debug(&format!("Popped {:04x}", value));
+    }
+
+    // only dynamic memory (the game can't write anywhere else either)
+    fn debug_poke(&mut self, arg: &str) {
+        let parts: Vec<_> = arg.split_whitespace().collect();
+
+        if parts.len() != 2 {
+            self.ui.debug("Usage: $poke <addr> <value>");
+            return;
+        }
+
+        let addr = usize::from_str_radix(parts[0].trim_start_matches("0x"), 16);
+        let value = Zmachine::parse_value(parts[1]);
+
+        match (addr, value) {
+            (Ok(addr), Some(value)) if addr < self.static_start && value <= 0xFF => {
+                let old = self.memory.read_byte(addr);
+                self.memory.write_byte(addr, value as u8);
+                self.memory_written(addr, 1);
+                self.ui.debug(&format!("{:x}: {:02x} -> {:02x}", addr, old, value));
+            }
+            (Ok(addr), Some(_)) if addr >= self.static_start => self.ui.debug(&format!(
+                "Only dynamic memory can be changed (below {:x})",
+                self.static_start
+            )),
+            (Ok(_), Some(value)) => self.ui.debug(&format!("Not a byte: {}", value)),
+            _ => self.ui.debug("Usage: $poke <addr (in hex)> <value>"),
+        }
+    }
+
+    /// The call stack, innermost routine first: each routine (as an address
+    /// and as it was called), the values passed in, where it returns to and
+    /// where its result goes
+    pub fn backtrace(&self) -> String {
+        let routines = self.frame_routines();
+        let mut out = String::new();
+
+        writeln!(out, "PC @ {:x}", self.pc).unwrap();
+
+        for (index, frame) in self.frames.iter().enumerate().rev() {
+            let (routine, packed) = routines[index];
+
+            // (the bottom frame isn't a routine in v1-5)
+            if index == 0 && routine == 0 {
+                writeln!(out, "#{:<3} main", index).unwrap();
+                continue;
+            }
+
+            let name = match routine {
+                0 => String::from("R????"),
+                _ => format!("{} [{:04x}]", self.routine_label(routine), packed),
+            };
+
+            let args = match frame.args() {
+                Some(args) => {
+                    let values = args.iter().map(|value| format!("{:04x}", value));
+                    values.collect::<Vec<_>>().join(", ")
+                }
+                None => format!("{} args", frame.arg_count),
+            };
+
+            // where in the source it is: the pc, or for the frames below,
+            // the call that's still running (just before where it returns to)
+            let at = match self.frames.get(index + 1) {
+                Some(callee) => callee.resume.checked_sub(1),
+                None => Some(self.pc),
+            };
+            let line = at
+                .and_then(|addr| self.debug_info.source_line(addr))
+                .map_or(String::new(), |line| format!(" at {}", line));
+
+            // (a variable of the routine it returns to)
+            let caller = if index > 0 { routines[index - 1].0 } else { 0 };
+            let store = frame
+                .store
+                .map_or(String::from("(discarded)"), |var| self.variable_name(var, caller));
+
+            writeln!(
+                out,
+                "#{:<3} {} ({}){} returns to {:x} -> {}",
+                index, name, args, line, frame.resume, store
+            ).unwrap();
+        }
+
+        out
+    }
+
+    // the routine for each frame: its own, or for frames restored from a
+    // save, the known routine with the instruction the frame is at
+    fn frame_routines(&self) -> Vec<(usize, u16)> {
+        let mut routines = None;
+
+        (0..self.frames.len())
+            .map(|index| {
+                let frame = &self.frames[index];
+
+                if frame.routine != 0 || index == 0 {
+                    return (frame.routine, frame.packed);
+                }
+
+                let pc = match self.frames.get(index + 1) {
+                    Some(next) => next.resume - 1,
+                    None => self.pc,
+                };
+
+                let routines = routines.get_or_insert_with(|| self.find_routines());
+
+                match routines.range(..=pc).next_back() {
+                    // (unpack(1) is the multiplier for packed addresses)
+                    Some((&addr, routine)) if pc < routine.end() => {
+                        (addr, (addr / self.unpack(1)) as u16)
+                    }
+                    _ => (0, 0),
+                }
+            })
+            .collect()
+    }
+
+    // sp, a local (of the given routine) or a global, by its name in the
+    // debug file if it has one
+    fn variable_name(&self, index: u8, routine: usize) -> String {
+        let name = match index {
+            0 => None,
+            1..=15 => self.debug_info.local_name(routine, index as usize - 1),
+            _ => self.debug_info.global_name(index - 16),
+        };
+
+        match (name, index) {
+            (Some(name), _) => name.to_string(),
+            (None, 0) => String::from("sp"),
+            (None, 1..=15) => format!("local{}", index - 1),
+            (None, _) => format!("g{}", index - 16),
+        }
+    }
+
+    // both the name and the number: "location (g0)"
+    fn variable_label(&self, index: u8, routine: usize) -> String {
+        let number = match index {
+            0 => return String::from("sp"),
+            1..=15 => format!("local{}", index - 1),
+            _ => format!("g{}", index - 16),
+        };
+
+        match self.variable_name(index, routine) {
+            ref name if *name == number => number,
+            name => format!("{} ({})", name, number),
+        }
+    }
+
+    /// Starts counting executed opcodes, instructions and time per routine
+    /// and per turn, and the deepest call stack (see `profile_report`)
+    pub fn start_profile(&mut self) {
+        let routines = self
+            .frame_routines()
+            .iter()
+            .map(|&(routine, _)| routine)
+            .collect::<Vec<_>>();
+
+        self.profile = Some(Profile::new(self.version, &routines));
+    }
+
+    /// A flat profile of the routines, a call graph and the opcode counts,
+    /// if it's profiling
+    pub fn profile_report(&self) -> Option<String> {
+        let label = |addr| match addr {
+            0 => String::from("main"),
+            _ => self.routine_label(addr),
+        };
+
+        self.profile.as_ref().map(|profile| profile.report(label))
+    }
+
+    fn pause_profile(&mut self) {
+        if let Some(ref mut profile) = self.profile {
+            profile.pause();
+        }
+    }
+
+    // at quit, if the profile goes to a file
+    fn write_profile(&mut self) {
+        if self.options.profile_file.is_empty() {
+            return;
+        }
+
+        let report = match self.profile_report() {
+            Some(report) => report,
+            None => return,
+        };
+
+        let path = self.options.profile_file.clone();
+
+        if let Err(err) = File::create(&path).and_then(|mut file| file.write_all(report.as_bytes())) {
+            self.ui.debug(&format!("Couldn't write the profile to {}: {}", path, err));
+        }
+    }
+
+    // starts profiling, or shows the results so far
+    fn debug_profile(&mut self, arg: &str) {
+        if arg == "off" {
+            self.profile = None;
+            self.ui.debug("[Profiling stopped]");
+            return;
+        }
+
+        match self.profile_report() {
+            Some(report) => self.ui.debug(&report),
+            None => {
+                self.start_profile();
+                self.ui.debug("[Profiling: $profile again shows the results, $profile off stops]");
+            }
+        }
+    }
+
+    // a routine's name from the debug file, or its address
+    fn routine_label(&self, addr: usize) -> String {
+        match self.debug_info.routine_name(addr) {
+            Some(name) => name.to_string(),
+            None => format!("R{:04x}", addr),
+        }
+    }
+
+    fn attribute_label(&self, attr: u16) -> String {
+        match self.debug_info.attribute_name(attr) {
+            Some(name) => name.to_string(),
+            None => attr.to_string(),
+        }
+    }
+
+    // a number, or a name from the debug file
+    fn parse_attribute(&self, input: &str) -> Option<u16> {
+        match input.parse::<u16>().ok().or_else(|| self.debug_info.attribute_number(input)) {
+            Some(num) if (num as usize) < self.attr_width * 8 => Some(num),
+            _ => None,
+        }
+    }
+
+    fn parse_property(&self, input: &str) -> Option<u16> {
+        match input.parse::<u16>().ok().or_else(|| self.debug_info.property_number(input)) {
+            Some(num) if num > 0 && num < if self.version <= 3 { 32 } else { 64 } => Some(num),
+            _ => None,
+        }
+    }
+
+    // "12 name [0, 1]" (with the name only if the debug file has it)
+    fn format_property(&self, prop: &ObjectProperty) -> String {
+        let slice = self.memory.read(prop.addr, prop.len as usize);
+
+        match self.debug_info.property_name(u16::from(prop.num)) {
+            Some(name) => format!("{:2} {} {:?}", prop.num, name, slice),
+            None => format!("{:2} {:?}", prop.num, slice),
+        }
+    }
+
+    // "[2, 5]", or "[light, open]" with a debug file
+    fn format_attributes(&self, obj_num: u16) -> String {
+        let attributes = (0..(self.attr_width * 8) as u16)
+            .filter(|attr| self.test_attr(obj_num, *attr) == 1)
+            .map(|attr| self.attribute_label(attr))
+            .collect::<Vec<_>>();
+
+        format!("[{}]", attributes.join(", "))
+    }
+
+    fn debug_dump(&mut self) {
+        let mut out = String::new();
+        writeln!(out, "PC @ {}", self.pc).unwrap();
+
+        for frame in &self.frames {
+            writeln!(out, "{}", frame).unwrap();
+        }
+
+        self.ui.debug(&out);
+    }
+
+    fn debug_object_simple(&mut self, obj_num: u16) {
+        self.ui.debug(&format!("\nObject #{}", obj_num));
+
+        let addr = self.get_object_addr(obj_num);
+        let mut read = self.memory.get_reader(addr);
+
+        if self.version <= 3 {
+            self.ui.debug(&format!(
+                "Attributes: {:08b} {:08b} {:08b} {:08b}",
+                read.byte(),
+                read.byte(),
+                read.byte(),
+                read.byte()
+            ));
+
+            self.ui.debug(&format!(
+                "Parent: {}, Sibling: {}, Child: {}",
+                read.byte(),
+                read.byte(),
+                read.byte()
+            ));
+        } else {
+            self.ui.debug(&format!(
+                "Attributes: {:08b} {:08b} {:08b} {:08b} {:08b} {:08b}",
+                read.byte(),
+                read.byte(),
+                read.byte(),
+                read.byte(),
+                read.byte(),
+                read.byte()
+            ));
+
+            self.ui.debug(&format!(
+                "Parent: {}, Sibling: {}, Child: {}",
+                read.word(),
+                read.word(),
+                read.word()
+            ));
+        }
+
+        let prop_addr = read.word() as usize;
+        self.ui.debug(&format!("Property table @ {:x}", prop_addr));
+
+        let text_length = self.memory.read_byte(prop_addr);
+        let short_name = if text_length > 0 {
+            self.read_zstring(prop_addr + 1)
+        } else {
+            String::new()
+        };
+
+        self.ui.debug(&format!("{} (len: {})\n", short_name, text_length));
+    }
+
+    fn get_object_number(&self, input: &str) -> u16 {
+        if let Ok(num) = input.parse() {
+            num
+        } else if let Some(num) = self.find_object(input) {
+            num
+        } else {
+            self.debug_info.object_number(input).unwrap_or_default()
+        }
+    }
+
+    fn debug_object(&mut self, input: &str) {
+        let num = self.get_object_number(input);
+        if num == 0 {
+            return;
+        }
+
+        let mut obj = Object::new(num, self);
+        self.add_object_children(&mut obj);
+        self.ui.debug(&obj.to_string());
+    }
+
+    fn debug_object_tree(&mut self) {
+        let tree = self.get_object_tree();
+        self.ui.debug(&tree.to_string());
+    }
+
+    fn debug_object_properties(&mut self, input: &str) {
+        let num = self.get_object_number(input);
+        if num == 0 {
+            return;
         }
 
         self.ui.debug(&format!("Object: #{}", num));
@@ -2349,15 +3984,11 @@ impl Zmachine {
         let first_addr = addr + str_length + 1;
 
         let mut prop = self.read_object_prop(first_addr);
-        let mut slice = self.memory.read(prop.addr, prop.len as usize);
-
-        self.ui.debug(&format!("{:2} {:?}", prop.num, slice));
+        self.ui.debug(&self.format_property(&prop));
 
         while prop.num != 0 {
             prop = self.read_object_prop(prop.next);
-            slice = self.memory.read(prop.addr, prop.len as usize);
-
-            self.ui.debug(&format!("{:2} {:?}", prop.num, slice));
+            self.ui.debug(&self.format_property(&prop));
         }
     }
 
@@ -2368,15 +3999,9 @@ impl Zmachine {
         }
 
         let name = self.get_object_name(num);
-        let mut attributes = Vec::new();
-
-        for i in 0..(self.attr_width * 8) as u16 {
-            if self.test_attr(num, i) == 1 {
-                attributes.push(i);
-            }
-        }
+        let attributes = self.format_attributes(num);
 
-        self.ui.debug(&format!("{} ({})\n{:?}", name, num, attributes));
+        self.ui.debug(&format!("{} ({})\n{}", name, num, attributes));
     }
 
     pub fn debug_object_details(&self, obj_num: u16) -> String {
@@ -2391,32 +4016,20 @@ impl Zmachine {
         let first_addr = addr + str_length + 1;
 
         let mut prop = self.read_object_prop(first_addr);
-        let mut slice = self.memory.read(prop.addr, prop.len as usize);
-
-        writeln!(out, "{:2} {:?}", prop.num, slice).unwrap();
+        writeln!(out, "{}", self.format_property(&prop)).unwrap();
 
         while prop.num != 0 {
             prop = self.read_object_prop(prop.next);
-            slice = self.memory.read(prop.addr, prop.len as usize);
-
-            writeln!(out, "{:2} {:?}", prop.num, slice).unwrap();
+            writeln!(out, "{}", self.format_property(&prop)).unwrap();
         }
 
-        let mut attributes = Vec::new();
-
-        for i in 0..(self.attr_width * 8) as u16 {
-            if self.test_attr(obj_num, i) == 1 {
-                attributes.push(i);
-            }
-        }
-
-        write!(out, "\nAttributes:\n{:?}", attributes).unwrap();
+        write!(out, "\nAttributes:\n{}", self.format_attributes(obj_num)).unwrap();
 
         out
     }
 
     fn debug_have_attribute(&mut self, attr_str: &str) {
-        let attr = attr_str.parse().unwrap_or(0);
+        let attr = self.parse_attribute(attr_str).unwrap_or(0);
         let mut objects = Vec::new();
 
         for obj_num in 1..self.get_total_object_count() + 1 {
@@ -2431,7 +4044,7 @@ impl Zmachine {
     }
 
     fn debug_have_property(&mut self, prop_str: &str) {
-        let prop_num = prop_str.parse().unwrap_or(0);
+        let prop_num = self.parse_property(prop_str).unwrap_or(0);
         let mut objects = Vec::new();
 
         for obj_num in 1..self.get_total_object_count() + 1 {
@@ -2514,78 +4127,461 @@ impl Zmachine {
     }
 
     pub fn debug_history(&mut self) {
-        let undo_count = self.undos.len();
-        let total = self.undos.len() + self.redos.len() + 1;
+        let mut out = String::from("History:\n");
+
+        {
+            let (timeline, current) = self.history.timeline();
+            let total = timeline.len();
+
+            for (i, node) in timeline.iter().enumerate() {
+                let arrow = if i == current { " -> " } else { "    " };
+                let label = node.label.as_ref().map_or(String::new(), |l| format!(" [{}]", l));
 
-        self.ui.debug(&"History:");
+                writeln!(out, "{}({}/{}) @ {}{}", arrow, i + 1, total, node.location, label).unwrap();
+            }
+        }
+
+        self.ui.debug(&out);
+    }
+
+    fn debug_branches(&mut self) {
+        let tree = self.history.tree_string();
+
+        if tree.is_empty() {
+            self.ui.debug("No history yet");
+        } else {
+            self.ui.debug(&tree);
+        }
+    }
 
-        for (i, state) in self.undos.iter().enumerate() {
-            let index = i + 1;
-            self.ui.debug(&format!("    ({}/{}) @ {}", index, total, state.0));
+    fn debug_label(&mut self, label: &str) {
+        if self.history.label(label) {
+            self.ui.debug(&format!("Labeled current state: {}", label));
+        } else {
+            self.ui.debug("No history yet");
         }
+    }
+
+    fn debug_mark(&mut self, name: &str) {
+        let current = self.history.current().map(|node| node.id);
+
+        let id = match current {
+            Some(id) => id,
+            None => {
+                self.ui.debug("No history yet, enter a command first");
+                return;
+            }
+        };
 
-        if let Some(ref current) = self.current_state {
-            let index = undo_count + 1;
-            self.ui.debug(&format!(" -> ({}/{}) @ {}", index, total, current.0));
+        let name = if name.is_empty() {
+            format!("{}", self.checkpoints.len() + 1)
+        } else {
+            String::from(name)
+        };
+
+        let (location, time) = self.get_status();
+        let status = if self.memory.read_byte(0x01) & 0b0000_0010 == 0 {
+            format!("score {}, turn {}", self.read_global(1) as i16, self.read_global(2))
+        } else {
+            format!("time {}", time)
+        };
+
+        let state = self.history.materialize(id);
+        let data = self.export_history_state(&state);
+
+        self.checkpoints.retain(|mark| mark.name != name);
+        self.checkpoints.push(Checkpoint {
+            name: name.clone(),
+            location,
+            status,
+            data,
+        });
+
+        self.ui.debug(&format!("Checkpoint set: {}", name));
+    }
+
+    fn debug_marks(&mut self) {
+        if self.checkpoints.is_empty() {
+            self.ui.debug("No checkpoints yet");
+            return;
         }
 
-        for (i, state) in self.redos.iter().rev().enumerate() {
-            let index = undo_count + i + 2;
-            self.ui.debug(&format!("    ({}/{}) @ {}", index, total, state.0));
+        let mut out = String::from("Checkpoints:\n");
+
+        for mark in &self.checkpoints {
+            writeln!(out, "    {} @ {} ({})", mark.name, mark.location, mark.status).unwrap();
         }
+
+        self.ui.debug(&out);
     }
 
     fn debug_routine(&mut self, routine_addr: usize) {
-        let mut read = self.memory.get_reader(routine_addr);
-        let mut locals = Vec::new();
-        let count = read.byte();
+        let listing = self
+            .disassemble_routine(routine_addr)
+            .unwrap_or_else(|| format!("No routine @ {:x}", routine_addr));
 
-        for _ in 0..count {
-            match self.version {
-                1...4 => locals.push(read.word()),
-                _ => locals.push(0),
-            };
+        self.ui.debug(&listing);
+    }
+}
+
+// a routine as the disassembler found it
+struct RoutineListing {
+    addr: usize,
+    locals: Vec<u16>,
+    // in address order
+    instructions: Vec<Instruction>,
+}
+
+impl RoutineListing {
+    // right after the last instruction
+    fn end(&self) -> usize {
+        self.instructions.last().map_or(self.addr + 1, |instr| instr.next)
+    }
+}
+
+// the disassembler never runs the game, so there's nothing to show
+struct NoUI;
+
+impl UI for NoUI {
+    fn new() -> Box<NoUI> {
+        Box::new(NoUI)
+    }
+
+    fn clear(&self) {}
+    fn print(&mut self, _text: &str) {}
+    fn debug(&mut self, _text: &str) {}
+    fn print_object(&mut self, _number: u16, _name: &str) {}
+    fn set_status_bar(&mut self, _left: &str, _right: &str) {}
+    fn split_window(&mut self, _lines: u16) {}
+    fn set_window(&mut self, _window: u16) {}
+    fn erase_window(&mut self, _window: i16) {}
+    fn erase_line(&mut self) {}
+    fn set_cursor(&mut self, _line: u16, _column: u16) {}
+    fn get_cursor(&self) -> (u16, u16) {
+        (1, 1)
+    }
+    fn set_text_style(&mut self, _style: u16) {}
+    fn get_screen_size(&mut self) -> (usize, usize) {
+        (80, 24)
+    }
+    fn reset(&mut self) {}
+    fn get_user_input(&mut self) -> String {
+        String::new()
+    }
+    fn get_user_char(&mut self) -> u16 {
+        13
+    }
+    fn set_completions(&mut self, _words: Vec<String>, _resolution: usize) {}
+    fn flush(&mut self) {}
+    fn message(&self, _mtype: &str, _msg: &str) {}
+}
+
+// disassembler (txd-style listings)
+impl Zmachine {
+    /// A machine that's only for listing the story file's routines (it has
+    /// no UI, so it can't run the game)
+    pub fn disassembler(data: Vec<u8>) -> Zmachine {
+        Zmachine::new(data, NoUI::new(), Options::default())
+    }
+
+    /// A listing of every routine that can be found in the story file (from
+    /// the start of the game, call targets, and packed addresses in globals
+    /// and properties) that starts between `from` and `to`
+    pub fn disassemble(&self, from: usize, to: usize) -> String {
+        let routines = self.find_routines();
+        let mut out = String::new();
+
+        for routine in routines.range(from..=to).map(|(_, routine)| routine) {
+            out.push_str(&self.format_routine(routine));
         }
 
-        let first_instr = self.decode_instruction(read.position());
-        let mut set: HashSet<Instruction> = HashSet::new();
+        out
+    }
 
-        fn follow(zvm: &Zmachine, set: &mut HashSet<Instruction>, instr: Instruction) {
-            if set.contains(&instr) {
-                return;
+    /// A listing of the routine with its header at `addr` (a byte address),
+    /// if there's one there
+    pub fn disassemble_routine(&self, addr: usize) -> Option<String> {
+        self.read_routine(addr).map(|routine| self.format_routine(&routine))
+    }
+
+    fn find_routines(&self) -> BTreeMap<usize, RoutineListing> {
+        let mut routines = BTreeMap::new();
+
+        let high_memory = self.memory.read_word(0x04) as usize;
+
+        // (the main routine's header is right before the first instruction)
+        self.add_routines(&mut routines, self.initial_pc - 1);
+
+        // a debug file knows exactly where every routine is
+        for addr in self.debug_info.routine_addrs() {
+            if addr >= high_memory {
+                self.add_routines(&mut routines, addr);
             }
+        }
 
-            let branch = match instr.branch {
-                Some(Branch { address: Some(addr), .. }) => Some(addr),
-                _ => None,
-            };
+        // anything else has to look like a routine in high memory, and not
+        // start in the middle of one that's already known
 
-            let next = if instr.advances() {
-                Some(instr.next)
-            } else {
-                None
-            };
+        for value in self.packed_address_candidates() {
+            let addr = self.unpack_routine_addr(value);
+            let inside = routines
+                .range(..=addr)
+                .next_back()
+                .into_iter()
+                .any(|(_, routine)| addr < routine.end());
+
+            if addr >= high_memory && !inside {
+                self.add_routines(&mut routines, addr);
+            }
+        }
 
-            set.insert(instr);
+        routines
+    }
 
-            if let Some(addr) = branch {
-                follow(zvm, set, zvm.decode_instruction(addr));
-            };
+    // the routine at addr and everything it calls
+    fn add_routines(&self, routines: &mut BTreeMap<usize, RoutineListing>, addr: usize) {
+        let mut todo = vec![addr];
 
-            if let Some(addr) = next {
-                follow(zvm, set, zvm.decode_instruction(addr));
-            };
+        while let Some(addr) = todo.pop() {
+            if routines.contains_key(&addr) {
+                continue;
+            }
+
+            if let Some(routine) = self.read_routine(addr) {
+                todo.extend(routine.instructions.iter().filter_map(|instr| self.call_target(instr)));
+                routines.insert(addr, routine);
+            }
+        }
+    }
+
+    // every word in the globals and in object properties (where games keep
+    // their action routines, daemons and such)
+    fn packed_address_candidates(&self) -> Vec<u16> {
+        let mut values = (0..240).map(|index| self.read_global(index)).collect::<Vec<_>>();
+
+        // (stopping at anything that runs off the end of the story file)
+        let length = self.memory.len();
+
+        for object in 1..=self.get_total_object_count() {
+            if self.get_object_addr(object) + self.obj_size > length {
+                break;
+            }
+
+            let addr = self.get_object_prop_table_addr(object);
+            if addr >= length {
+                continue;
+            }
+
+            let str_length = self.memory.read_byte(addr) as usize * 2; // words in name
+            let mut next = addr + str_length + 1;
+
+            while next + 1 < length {
+                let prop = self.read_object_prop(next);
+                if prop.num == 0 || prop.next > length {
+                    break;
+                }
+
+                for index in 0..prop.len as usize / 2 {
+                    values.push(self.memory.read_word(prop.addr + index * 2));
+                }
+
+                next = prop.next;
+            }
+        }
+
+        values.retain(|value| *value != 0);
+        values
+    }
+
+    // follows the control flow from the first instruction (None if that runs
+    // into something that isn't code)
+    fn read_routine(&self, addr: usize) -> Option<RoutineListing> {
+        if addr >= self.memory.len() || self.memory.read_byte(addr) > 15 {
+            return None;
+        }
+
+        // (v1-4 have the initial values of the locals in the header)
+        let header = if self.version <= 4 { 1 + self.memory.read_byte(addr) as usize * 2 } else { 1 };
+        if addr + header > self.memory.len() {
+            return None;
+        }
+
+        let mut read = self.memory.get_reader(addr);
+        let count = read.byte();
+        let locals = (0..count)
+            .map(|_| if self.version <= 4 { read.word() } else { 0 })
+            .collect();
+
+        let mut found = BTreeMap::new();
+        let mut todo = vec![read.position()];
+
+        while let Some(pc) = todo.pop() {
+            if found.contains_key(&pc) {
+                continue;
+            }
+
+            // (room for the longest instruction, not counting text, so a
+            // guess that runs off the end doesn't read past the story file)
+            if pc >= self.memory.len() || self.memory.len() - pc < 23 {
+                return None;
+            }
+
+            let instr = self.try_decode_instruction(pc)?;
+
+            if let Some(Branch { address: Some(addr), .. }) = instr.branch {
+                todo.push(addr);
+            }
+
+            if let Some(addr) = self.jump_target(&instr) {
+                todo.push(addr);
+            }
+
+            if instr.advances() {
+                todo.push(instr.next);
+            }
+
+            found.insert(pc, instr);
+        }
+
+        Some(RoutineListing {
+            addr,
+            locals,
+            instructions: found.into_values().collect(),
+        })
+    }
+
+    fn jump_target(&self, instr: &Instruction) -> Option<usize> {
+        let offset = match (instr.opcode, instr.operands.first()) {
+            (Opcode::OP1_140, Some(&Operand::Large(offset))) => offset as i16 as isize,
+            (Opcode::OP1_140, Some(&Operand::Small(offset))) => offset as isize,
+            _ => return None,
         };
 
-        follow(self, &mut set, first_instr);
+        // (targets outside the story file aren't code)
+        let target = (instr.next as isize).checked_add(offset - 2)?;
+
+        if target < 0 || target as usize >= self.memory.len() {
+            return None;
+        }
+
+        Some(target as usize)
+    }
+
+    fn call_target(&self, instr: &Instruction) -> Option<usize> {
+        if !instr.does_call(self.version) {
+            return None;
+        }
+
+        match instr.operands.first() {
+            Some(&Operand::Large(addr)) if addr != 0 => Some(self.unpack_routine_addr(addr)),
+            Some(&Operand::Small(addr)) if addr != 0 => {
+                Some(self.unpack_routine_addr(u16::from(addr)))
+            }
+            _ => None,
+        }
+    }
+
+    fn format_routine(&self, routine: &RoutineListing) -> String {
+        // branch and jump targets get labels, in address order
+        let mut targets = BTreeSet::new();
+
+        for instr in &routine.instructions {
+            if let Some(Branch { address: Some(addr), .. }) = instr.branch {
+                targets.insert(addr);
+            }
+
+            if let Some(addr) = self.jump_target(instr) {
+                targets.insert(addr);
+            }
+        }
+
+        let labels = targets
+            .iter()
+            .enumerate()
+            .map(|(index, addr)| (*addr, format!("L{:04}", index + 1)))
+            .collect::<HashMap<_, _>>();
 
-        let mut instructions = set.iter().collect::<Vec<_>>();
-        instructions.sort_by_key(|i| i.addr);
+        let locals = routine
+            .locals
+            .iter()
+            .map(|value| format!("{:04x}", value))
+            .collect::<Vec<_>>();
 
-        self.ui.debug(&format!("Locals: {:?}", locals));
+        let name = self
+            .debug_info
+            .routine_name(routine.addr)
+            .map_or(String::new(), |name| format!(" ({})", name));
 
-        for instr in &instructions {
-            self.ui.debug(&format!("{}", instr));
+        let mut out = String::new();
+        writeln!(
+            out,
+            "Routine R{:04x}{}, {} locals ({})\n",
+            routine.addr,
+            name,
+            locals.len(),
+            locals.join(", ")
+        ).unwrap();
+
+        for instr in &routine.instructions {
+            let label = labels.get(&instr.addr).map_or(String::new(), |label| format!("{}:", label));
+            let instr = self.format_instruction(instr, routine.addr, &labels);
+            writeln!(out, "{:6} {}", label, instr).unwrap();
         }
+
+        out.push('\n');
+        out
+    }
+
+    // like the Display for instructions, but with labels for branches and
+    // jumps, routine addresses for calls, and names from the debug file
+    fn format_instruction(
+        &self,
+        instr: &Instruction,
+        routine: usize,
+        labels: &HashMap<usize, String>,
+    ) -> String {
+        let mut out = format!("{:5x}: {:16}", instr.addr, instr.name);
+        let call = self.call_target(instr);
+        let jump = self.jump_target(instr).and_then(|addr| labels.get(&addr));
+
+        let takes_variable = instr.takes_variable(self.version);
+
+        for (index, op) in instr.operands.iter().enumerate() {
+            match (index, call, jump, op) {
+                (0, Some(addr), _, _) => write!(out, " {}", self.routine_label(addr)),
+                (0, _, Some(label), _) => write!(out, " {}", label),
+                // (a variable given by number, or by another variable's value)
+                (0, _, _, &Operand::Small(var)) if takes_variable => {
+                    write!(out, " {}", self.variable_name(var, routine))
+                }
+                (0, _, _, &Operand::Variable(var)) if takes_variable => {
+                    write!(out, " [{}]", self.variable_name(var, routine))
+                }
+                (_, _, _, &Operand::Variable(var)) => write!(out, " {}", self.variable_name(var, routine)),
+                _ => write!(out, " {}", op),
+            }.unwrap();
+        }
+
+        if let Some(var) = instr.store {
+            write!(out, " -> {}", self.variable_name(var, routine)).unwrap();
+        }
+
+        if let Some(ref branch) = instr.branch {
+            let not = if branch.condition == 0 { "~" } else { "" };
+
+            match (branch.address.and_then(|addr| labels.get(&addr)), branch.returns) {
+                (Some(label), _) => write!(out, " ?{}{}", not, label),
+                (None, Some(1)) => write!(out, " ?{}rtrue", not),
+                (None, Some(0)) => write!(out, " ?{}rfalse", not),
+                _ => Ok(()),
+            }.unwrap();
+        }
+
+        // (new lines as ^, like txd, to keep one instruction per line)
+        if let Some(ref text) = instr.text {
+            write!(out, " \"{}\"", text.replace('\n', "^")).unwrap();
+        }
+
+        out
     }
 }
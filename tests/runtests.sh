@@ -11,3 +11,7 @@ python regtest.py -i "../target/debug/encrusted" praxix.z5.regtest
 # Game tests
 python regtest.py -i "../target/debug/encrusted" curses.z3.regtest
 python regtest.py -i "../target/debug/encrusted" minizork.z3.regtest
+
+# RemGlk JSON mode
+python regtest.py -i "../target/debug/encrusted --rem" -r czech.z3.regtest
+python regtest.py -i "../target/debug/encrusted --rem" -r minizork.z3.regtest